@@ -1,22 +1,116 @@
-use num_derive::FromPrimitive; 
+use num_derive::FromPrimitive;
 
-pub type Value = f64;
+use crate::value::Value;
 
 #[derive(Debug, PartialEq, FromPrimitive)]
 pub enum OpCode {
     Constant,
+    ConstantLong,
+    Nil,
+    True,
+    False,
+    Pop,
+    GetLocal,
+    SetLocal,
+    GetGlobal,
+    GetGlobalLong,
+    DefineGlobal,
+    DefineGlobalLong,
+    SetGlobal,
+    SetGlobalLong,
+    GetUpvalue,
+    SetUpvalue,
+    GetProperty,
+    GetPropertyLong,
+    SetProperty,
+    SetPropertyLong,
+    GetSuper,
+    GetSuperLong,
+    Equal,
+    Greater,
+    Less,
     Add,
     Subtract,
     Multiply,
     Divide,
+    Not,
     Negate,
-    Return
+    Print,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Invoke,
+    InvokeLong,
+    SuperInvoke,
+    SuperInvokeLong,
+    Closure,
+    ClosureLong,
+    CloseUpvalue,
+    Return,
+    Class,
+    ClassLong,
+    Inherit,
+    Method,
+    MethodLong,
 }
 
+impl OpCode {
+    /// The variant of an instruction that takes its constant index as a
+    /// 24-bit operand instead of a single byte.
+    pub fn long_form(&self) -> OpCode {
+        return match self {
+            OpCode::Constant => OpCode::ConstantLong,
+            OpCode::GetGlobal => OpCode::GetGlobalLong,
+            OpCode::DefineGlobal => OpCode::DefineGlobalLong,
+            OpCode::SetGlobal => OpCode::SetGlobalLong,
+            OpCode::GetProperty => OpCode::GetPropertyLong,
+            OpCode::SetProperty => OpCode::SetPropertyLong,
+            OpCode::GetSuper => OpCode::GetSuperLong,
+            OpCode::Invoke => OpCode::InvokeLong,
+            OpCode::SuperInvoke => OpCode::SuperInvokeLong,
+            OpCode::Closure => OpCode::ClosureLong,
+            OpCode::Class => OpCode::ClassLong,
+            OpCode::Method => OpCode::MethodLong,
+            _ => unreachable!("{:?} has no constant operand.", self),
+        };
+    }
+}
+
+/// Largest constant index the long form of an instruction can address with
+/// its 24-bit operand.
+pub const CONSTANT_LONG_MAX: usize = (1 << 24) - 1;
+
 pub struct Chunk {
    pub code: Vec<u8>,
    pub constants: Vec<Value>,
-   pub lines: Vec<i32>,
+   pub lines: Vec<LineRun>,
+   pub columns: Vec<ColumnRun>,
+   // Where the last column run starts, so the next one can be stored as a
+   // distance from it.
+   last_column_offset: usize,
+}
+
+/// Source line of a run of consecutive bytes, starting at `offset` and lasting
+/// until the next run starts. Most lines compile to a single run.
+pub struct LineRun {
+    pub offset: u32,
+    pub line: i32,
+}
+
+/// Source column of a run of consecutive bytes compiled from the same token.
+/// Columns change far more often than lines, so runs are kept small: each
+/// starts `delta` bytes after the previous one. A column of 0 means unknown,
+/// for tokens too far along their line to fit.
+pub struct ColumnRun {
+    pub delta: u16,
+    pub column: u16,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        return Chunk::new();
+    }
 }
 
 impl Chunk {
@@ -24,22 +118,154 @@ impl Chunk {
         return Chunk {
             code: vec![],
             constants: vec![],
-            lines: vec![]
+            lines: vec![],
+            columns: vec![],
+            last_column_offset: 0,
         };
     }
 
-    pub fn write(&mut self, byte: u8, line: i32) {
+    pub fn write(&mut self, byte: u8, line: i32, column: i32) {
+        let offset = self.code.len();
+
+        let same_line = match self.lines.last() {
+            Some(run) => run.line == line,
+            None => false,
+        };
+
+        if !same_line {
+            self.lines.push(LineRun {
+                offset: offset as u32,
+                line,
+            });
+        }
+
+        let column = u16::try_from(column).unwrap_or(0);
+        let same_column = match self.columns.last() {
+            Some(run) => run.column == column,
+            None => false,
+        };
+
+        if !same_column {
+            // Runs are at most a few bytes long, but keep the deltas in range
+            // regardless by carrying the previous column forward.
+            let mut delta = offset - self.last_column_offset;
+            while delta > u16::MAX as usize {
+                let previous = self.columns.last().map_or(0, |run| run.column);
+                self.columns.push(ColumnRun {
+                    delta: u16::MAX,
+                    column: previous,
+                });
+                delta -= u16::MAX as usize;
+            }
+
+            self.columns.push(ColumnRun {
+                delta: delta as u16,
+                column,
+            });
+            self.last_column_offset = offset;
+        }
+
         self.code.push(byte);
-        self.lines.push(line);
     }
 
-    pub fn add_constant(&mut self, value: Value) -> u8 {
+    pub fn get_line(&self, offset: usize) -> i32 {
+        let index = self.lines.partition_point(|run| run.offset as usize <= offset);
+        return self.lines[index - 1].line;
+    }
+
+    /// Only needed when reporting errors, so this walks the runs rather than
+    /// storing their offsets.
+    pub fn get_column(&self, offset: usize) -> i32 {
+        let mut start = 0;
+        let mut column = 0;
+
+        for run in &self.columns {
+            start += run.delta as usize;
+            if start > offset {
+                break;
+            }
+
+            column = run.column;
+        }
+
+        return column as i32;
+    }
+
+    pub fn add_constant(&mut self, value: Value) -> usize {
         self.constants.push(value);
-        return (self.constants.len() - 1) as u8;
+        return self.constants.len() - 1;
+    }
+
+    /// Adds `value` to the constant table and emits the instruction that
+    /// loads it. Returns false if the constant table is full.
+    pub fn write_constant(&mut self, value: Value, line: i32, column: i32) -> bool {
+        let index = self.add_constant(value);
+        if index > CONSTANT_LONG_MAX {
+            return false;
+        }
+
+        self.write_indexed(OpCode::Constant, index, line, column);
+        return true;
+    }
+
+    /// Emits `op` with the constant `index` as its operand, switching to the
+    /// long form of `op` once the index no longer fits in a single byte.
+    pub fn write_indexed(&mut self, op: OpCode, index: usize, line: i32, column: i32) {
+        if index <= u8::MAX as usize {
+            self.write(op as u8, line, column);
+            self.write(index as u8, line, column);
+        } else {
+            self.write(op.long_form() as u8, line, column);
+            self.write(((index >> 16) & 0xff) as u8, line, column);
+            self.write(((index >> 8) & 0xff) as u8, line, column);
+            self.write((index & 0xff) as u8, line, column);
+        }
     }
 
-    pub fn read_constant(&self, index: u8) -> Value {
-        return self.constants[index as usize];
+    pub fn read_constant(&self, index: usize) -> Value {
+        return self.constants[index];
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_one_line_run_per_line() {
+        let mut chunk = Chunk::new();
+        for line in [1, 1, 1, 2, 2, 5] {
+            chunk.write(0, line, 1);
+        }
+
+        assert_eq!(chunk.lines.len(), 3);
+        let lines: Vec<i32> = (0..6).map(|offset| chunk.get_line(offset)).collect();
+        assert_eq!(lines, [1, 1, 1, 2, 2, 5]);
+    }
+
+    #[test]
+    fn stores_one_column_run_per_token() {
+        let mut chunk = Chunk::new();
+        for column in [1, 1, 4, 4, 4, 9, 70_000] {
+            chunk.write(0, 1, column);
+        }
+
+        assert_eq!(chunk.columns.len(), 4);
+        let columns: Vec<i32> = (0..7).map(|offset| chunk.get_column(offset)).collect();
+        // Columns that don't fit are unknown.
+        assert_eq!(columns, [1, 1, 4, 4, 4, 9, 0]);
+    }
+
+    #[test]
+    fn finds_columns_after_runs_longer_than_a_delta() {
+        let mut chunk = Chunk::new();
+        for _ in 0..70_000 {
+            chunk.write(0, 1, 3);
+        }
+        chunk.write(0, 1, 7);
+
+        assert_eq!(chunk.get_column(0), 3);
+        assert_eq!(chunk.get_column(69_999), 3);
+        assert_eq!(chunk.get_column(70_000), 7);
+    }
+}
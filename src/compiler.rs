@@ -1,21 +1,166 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+};
+
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 
 use crate::{
-    chunk::{Chunk, OpCode, Value},
+    chunk::{Chunk, OpCode, CONSTANT_LONG_MAX},
     debug::disassemble_chunk,
-    scanner::{Scanner, Token, TokenType},
+    diagnostic::{Diagnostic, Label, Severity},
+    memory::Heap,
+    object::{ObjFunction, ObjKind, ObjRef},
+    scanner::{Scanner, Span, Token, TokenType},
+    value::Value,
 };
 
+/// Debugging output produced while compiling. Only takes effect when built
+/// with the `trace` feature.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompileOptions {
+    /// Disassemble each function's chunk once it compiles without errors.
+    pub dump_bytecode: bool,
+}
+
 struct Parser<'a> {
-    current: Token,
-    previous: Token,
+    current: Token<'a>,
+    previous: Token<'a>,
     scanner: Scanner<'a>,
-    chunk: Chunk,
-    had_error: bool,
+    heap: &'a mut Heap,
+    compiler: Box<Compiler<'a>>,
+    classes: Vec<ClassCompiler>,
+    diagnostics: Vec<Diagnostic>,
     panic_mode: bool,
+    options: CompileOptions,
+    script: u32,
+    debug_output: &'a mut dyn Write,
+}
+
+/// Per-function compilation state. Each function body being compiled gets its
+/// own `Compiler`, linked to the one for the function that encloses it.
+struct Compiler<'a> {
+    enclosing: Option<Box<Compiler<'a>>>,
+    function: ObjFunction,
+    function_type: FunctionType,
+    locals: Vec<Local<'a>>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: i32,
+    // Constant slots of the names used by this function, so that each name
+    // is only added once.
+    names: HashMap<ObjRef, usize>,
 }
 
+/// Tracks the class whose body is currently being compiled.
+struct ClassCompiler {
+    has_superclass: bool,
+}
+
+#[derive(PartialEq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(function_type: FunctionType, name: Option<ObjRef>, script: u32) -> Box<Compiler<'a>> {
+        let mut compiler = Box::new(Compiler {
+            enclosing: None,
+            function: ObjFunction::new(name, script),
+            function_type,
+            locals: Vec::new(),
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            names: HashMap::new(),
+        });
+
+        // Slot zero holds the function being called, or the receiver for
+        // methods.
+        let slot_zero = match compiler.function_type {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            FunctionType::Function | FunctionType::Script => "",
+        };
+        compiler.locals.push(Local {
+            name: synthetic_token(slot_zero),
+            depth: 0,
+            is_captured: false,
+        });
+
+        return compiler;
+    }
+
+    fn resolve_local(&self, name: &Token) -> Result<Option<u8>, &'static str> {
+        for (index, local) in self.locals.iter().enumerate().rev() {
+            if local.name.lexeme == name.lexeme {
+                if local.depth == -1 {
+                    return Err("Can't read local variable in its own initializer.");
+                }
+
+                return Ok(Some(index as u8));
+            }
+        }
+
+        return Ok(None);
+    }
+
+    /// Looks `name` up in the enclosing functions, threading an upvalue
+    /// through each function between the one that declares the variable and
+    /// this one.
+    fn resolve_upvalue(&mut self, name: &Token) -> Result<Option<u8>, &'static str> {
+        let enclosing = match self.enclosing.as_mut() {
+            Some(enclosing) => enclosing,
+            None => return Ok(None),
+        };
+
+        if let Some(local) = enclosing.resolve_local(name)? {
+            enclosing.locals[local as usize].is_captured = true;
+            return self.add_upvalue(local, true).map(Some);
+        }
+
+        if let Some(upvalue) = enclosing.resolve_upvalue(name)? {
+            return self.add_upvalue(upvalue, false).map(Some);
+        }
+
+        return Ok(None);
+    }
+
+    fn add_upvalue(&mut self, index: u8, is_local: bool) -> Result<u8, &'static str> {
+        for (i, upvalue) in self.upvalues.iter().enumerate() {
+            if upvalue.index == index && upvalue.is_local == is_local {
+                return Ok(i as u8);
+            }
+        }
+
+        if self.upvalues.len() == UPVALUES_MAX {
+            return Err("Too many closure variables in function.");
+        }
+
+        self.upvalues.push(Upvalue { index, is_local });
+        self.function.upvalue_count = self.upvalues.len();
+        return Ok((self.upvalues.len() - 1) as u8);
+    }
+}
+
+struct Local<'a> {
+    name: Token<'a>,
+    // -1 while the variable's initializer is still being compiled.
+    depth: i32,
+    is_captured: bool,
+}
+
+struct Upvalue {
+    index: u8,
+    // Whether this captures a local of the enclosing function, or one of its
+    // upvalues.
+    is_local: bool,
+}
+
+const LOCALS_MAX: usize = u8::MAX as usize + 1;
+const UPVALUES_MAX: usize = u8::MAX as usize + 1;
+
 #[derive(Debug, FromPrimitive)]
 enum Precedence {
     None,
@@ -32,25 +177,36 @@ enum Precedence {
 }
 
 struct ParseRule {
-    prefix: Option<fn(&mut Parser)>,
-    infix: Option<fn(&mut Parser)>,
+    prefix: Option<fn(&mut Parser, bool)>,
+    infix: Option<fn(&mut Parser, bool)>,
     precedence: Precedence,
 }
 
 impl<'a> Parser<'a> {
-    fn new(source: &'a Vec<char>) -> Parser<'a> {
+    fn new(
+        source: &'a str,
+        heap: &'a mut Heap,
+        options: CompileOptions,
+        script: u32,
+        debug_output: &'a mut dyn Write,
+    ) -> Parser<'a> {
         return Parser {
             current: Token::empty(),
             previous: Token::empty(),
             scanner: Scanner::new(source),
-            chunk: Chunk::new(),
-            had_error: false,
+            heap,
+            compiler: Compiler::new(FunctionType::Script, None, script),
+            classes: Vec::new(),
+            diagnostics: Vec::new(),
             panic_mode: false,
+            options,
+            script,
+            debug_output,
         };
     }
 
     fn advance(&mut self) {
-        self.previous = self.current.clone();
+        self.previous = self.current;
 
         loop {
             self.current = self.scanner.scan_token();
@@ -59,7 +215,7 @@ impl<'a> Parser<'a> {
                 return;
             };
 
-            let lexeme = &self.current.lexeme.clone();
+            let lexeme = self.current.lexeme;
             self.error_at_current(lexeme)
         }
     }
@@ -73,16 +229,476 @@ impl<'a> Parser<'a> {
         self.error_at_current(&message);
     }
 
+    fn check(&self, token_type: TokenType) -> bool {
+        return self.current.token_type == token_type;
+    }
+
+    fn match_token(&mut self, token_type: TokenType) -> bool {
+        if !self.check(token_type) {
+            return false;
+        }
+
+        self.advance();
+        return true;
+    }
+
     fn expression(&mut self) {
         self.parse_precendence(Precedence::Assignment);
     }
 
-    fn end_compiler(&mut self) {
+    fn declaration(&mut self) {
+        if self.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_token(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.".to_owned());
+        let class_name = self.previous;
+        let name_constant = self.identifier_constant(class_name);
+        self.declare_variable();
+
+        self.emit_indexed(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassCompiler {
+            has_superclass: false,
+        });
+
+        if self.match_token(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.".to_owned());
+            self.variable(false);
+
+            if class_name.lexeme == self.previous.lexeme {
+                self.error("A class can't inherit from itself.");
+            }
+
+            // Methods reach the superclass through a local named 'super' in a
+            // scope wrapping the class body.
+            self.begin_scope();
+            self.add_local(synthetic_token("super"));
+            self.define_variable(0);
+
+            self.named_variable(class_name, false);
+            self.emit_byte(OpCode::Inherit as u8);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        // Load the class back onto the stack so methods can be bound to it.
+        self.named_variable(class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.".to_owned());
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.".to_owned());
+        self.emit_byte(OpCode::Pop as u8);
+
+        if self.classes.last().unwrap().has_superclass {
+            self.end_scope();
+        }
+
+        self.classes.pop();
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.".to_owned());
+        let constant = self.identifier_constant(self.previous);
+
+        let function_type = if self.previous.lexeme == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+
+        self.emit_indexed(OpCode::Method, constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, function_type: FunctionType) {
+        let name = self.heap.copy_string(self.previous.lexeme);
+        let enclosing = std::mem::replace(&mut self.compiler, Compiler::new(function_type, Some(name), self.script));
+        self.compiler.enclosing = Some(enclosing);
+
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.".to_owned());
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.compiler.function.arity += 1;
+                if self.compiler.function.arity > u8::MAX as usize {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.".to_owned());
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.".to_owned());
+        self.block();
+
+        let (function, upvalues) = self.end_compiler();
+        let function = self.heap.allocate(ObjKind::Function(function));
+        let constant = self.make_constant(Value::Obj(function));
+        self.emit_indexed(OpCode::Closure, constant);
+
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index);
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.match_token(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_byte(OpCode::Nil as u8);
+        }
+
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.".to_owned(),
+        );
+
+        self.define_variable(global);
+    }
+
+    fn statement(&mut self) {
+        if self.match_token(TokenType::Print) {
+            self.print_statement();
+        } else if self.match_token(TokenType::For) {
+            self.for_statement();
+        } else if self.match_token(TokenType::If) {
+            self.if_statement();
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.match_token(TokenType::While) {
+            self.while_statement();
+        } else if self.match_token(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            self.declaration();
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after block.".to_owned());
+    }
+
+    fn begin_scope(&mut self) {
+        self.compiler.scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.compiler.scope_depth -= 1;
+
+        while let Some(local) = self.compiler.locals.last() {
+            if local.depth <= self.compiler.scope_depth {
+                break;
+            }
+
+            if local.is_captured {
+                self.emit_byte(OpCode::CloseUpvalue as u8);
+            } else {
+                self.emit_byte(OpCode::Pop as u8);
+            }
+            self.compiler.locals.pop();
+        }
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.".to_owned());
+        self.emit_byte(OpCode::Print as u8);
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.".to_owned());
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.".to_owned());
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::Pop as u8);
+
+        if self.match_token(TokenType::Else) {
+            self.statement();
+        }
+
+        self.patch_jump(else_jump);
+    }
+
+    fn return_statement(&mut self) {
+        if self.compiler.function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.match_token(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.compiler.function_type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.".to_owned());
+            self.emit_byte(OpCode::Return as u8);
+        }
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.".to_owned());
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.".to_owned());
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop as u8);
+    }
+
+    fn for_statement(&mut self) {
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.".to_owned());
+        if self.match_token(TokenType::Semicolon) {
+            // No initializer.
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+
+        let mut exit_jump = None;
+        if !self.match_token(TokenType::Semicolon) {
+            self.expression();
+            self.consume(
+                TokenType::Semicolon,
+                "Expect ';' after loop condition.".to_owned(),
+            );
+
+            // Jump out of the loop if the condition is false.
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_byte(OpCode::Pop as u8); // Condition.
+        }
+
+        if !self.match_token(TokenType::RightParen) {
+            // The increment runs after the body, so jump over it now and
+            // loop back to it once the body is done.
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.current_chunk().code.len();
+
+            self.expression();
+            self.emit_byte(OpCode::Pop as u8);
+            self.consume(
+                TokenType::RightParen,
+                "Expect ')' after for clauses.".to_owned(),
+            );
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::Pop as u8); // Condition.
+        }
+
+        self.end_scope();
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after expression.".to_owned(),
+        );
+        self.emit_byte(OpCode::Pop as u8);
+    }
+
+    fn parse_variable(&mut self, message: &str) -> usize {
+        self.consume(TokenType::Identifier, message.to_owned());
+
+        self.declare_variable();
+        if self.compiler.scope_depth > 0 {
+            return 0;
+        }
+
+        return self.identifier_constant(self.previous);
+    }
+
+    fn declare_variable(&mut self) {
+        if self.compiler.scope_depth == 0 {
+            return;
+        }
+
+        let name = self.previous;
+
+        let mut duplicate = None;
+        for local in self.compiler.locals.iter().rev() {
+            if local.depth != -1 && local.depth < self.compiler.scope_depth {
+                break;
+            }
+
+            if local.name.lexeme == name.lexeme {
+                duplicate = Some(local.name);
+                break;
+            }
+        }
+
+        if let Some(previous) = duplicate {
+            self.error_with_label(
+                "Already a variable with this name in this scope.",
+                &previous,
+                "first declared here",
+            );
+        }
+
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: Token<'a>) {
+        if self.compiler.locals.len() == LOCALS_MAX {
+            self.error("Too many local variables in function.");
+            return;
+        }
+
+        self.compiler.locals.push(Local {
+            name,
+            depth: -1,
+            is_captured: false,
+        });
+    }
+
+    fn mark_initialized(&mut self) {
+        if self.compiler.scope_depth == 0 {
+            return;
+        }
+
+        if let Some(local) = self.compiler.locals.last_mut() {
+            local.depth = self.compiler.scope_depth;
+        }
+    }
+
+    fn identifier_constant(&mut self, name: Token) -> usize {
+        let string = self.heap.copy_string(name.lexeme);
+
+        // Names are interned, so reuse the slot if this name was already
+        // added.
+        if let Some(index) = self.compiler.names.get(&string) {
+            return *index;
+        }
+
+        let index = self.make_constant(Value::Obj(string));
+        self.compiler.names.insert(string, index);
+        return index;
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.compiler.scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_indexed(OpCode::DefineGlobal, global);
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        return &mut self.compiler.function.chunk;
+    }
+
+    fn end_compiler(&mut self) -> (ObjFunction, Vec<Upvalue>) {
         self.emit_return();
+
+        let enclosing = self.compiler.enclosing.take();
+        let compiler = match enclosing {
+            Some(enclosing) => std::mem::replace(&mut self.compiler, enclosing),
+            None => {
+                let placeholder = Compiler::new(FunctionType::Script, None, self.script);
+                std::mem::replace(&mut self.compiler, placeholder)
+            }
+        };
+
+        if cfg!(feature = "trace") && self.options.dump_bytecode && self.diagnostics.is_empty() {
+            // Dumps are a debugging aid, so failing to write one is not a
+            // compile error.
+            let _ = disassemble_chunk(
+                self.debug_output,
+                &compiler.function.chunk,
+                &compiler.function.to_string(),
+            );
+        }
+
+        return (compiler.function, compiler.upvalues);
     }
 
     fn emit_byte(&mut self, byte: u8) {
-        self.chunk.write(byte, self.previous.line);
+        let (line, column) = (self.previous.line, self.previous.column);
+        self.current_chunk().write(byte, line, column);
+    }
+
+    /// Emits `bytes` attributed to `token` instead of the previous token, so
+    /// that runtime errors point at an operator rather than its last operand.
+    fn emit_bytes_at(&mut self, token: &Token, bytes: &[u8]) {
+        for byte in bytes {
+            self.current_chunk().write(*byte, token.line, token.column);
+        }
+    }
+
+    /// Emits `op` with a constant index operand, using the long form of the
+    /// instruction if the index needs it.
+    fn emit_indexed(&mut self, op: OpCode, index: usize) {
+        let (line, column) = (self.previous.line, self.previous.column);
+        self.current_chunk().write_indexed(op, index, line, column);
+    }
+
+    /// Like `emit_indexed`, but attributed to `token`.
+    fn emit_indexed_at(&mut self, token: &Token, op: OpCode, index: usize) {
+        self.current_chunk().write_indexed(op, index, token.line, token.column);
     }
 
     fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
@@ -90,59 +706,288 @@ impl<'a> Parser<'a> {
         self.emit_byte(byte2);
     }
 
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::Loop as u8);
+
+        // +2 to also skip over the operand of the Loop instruction itself.
+        let offset = self.current_chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+
+        self.emit_bytes(((offset >> 8) & 0xff) as u8, (offset & 0xff) as u8);
+    }
+
+    /// Emits a jump with a placeholder operand and returns the operand's
+    /// offset so it can be filled in later with `patch_jump`.
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_byte(instruction as u8);
+        self.emit_bytes(0xff, 0xff);
+        return self.current_chunk().code.len() - 2;
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the jump offset itself.
+        let jump = self.current_chunk().code.len() - offset - 2;
+
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.");
+        }
+
+        self.current_chunk().code[offset] = ((jump >> 8) & 0xff) as u8;
+        self.current_chunk().code[offset + 1] = (jump & 0xff) as u8;
+    }
+
     fn emit_return(&mut self) {
+        if self.compiler.function_type == FunctionType::Initializer {
+            self.emit_bytes(OpCode::GetLocal as u8, 0);
+        } else {
+            self.emit_byte(OpCode::Nil as u8);
+        }
+
         self.emit_byte(OpCode::Return as u8);
     }
 
     fn error_at_current(&mut self, message: &str) {
-        self.error_at(&self.current.clone(), message);
+        self.error_at(self.current, message);
     }
 
     fn error(&mut self, message: &str) {
-        self.error_at(&self.previous.clone(), message);
+        self.error_at(self.previous, message);
     }
 
-    fn error_at(&mut self, token: &Token, message: &str) {
+    /// Reports an error at the previous token that also points at `token`.
+    fn error_with_label(&mut self, message: &str, token: &Token, label: &str) {
         if self.panic_mode {
             return;
         }
 
-        self.panic_mode = true;
+        self.error(message);
+
+        if let Some(diagnostic) = self.diagnostics.last_mut() {
+            diagnostic.labels.push(Label::at(token, label));
+        }
+    }
+
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while self.current.token_type != TokenType::EOF {
+            if self.previous.token_type == TokenType::Semicolon {
+                return;
+            }
 
-        eprint!("[line {}] Error", token.line);
+            match self.current.token_type {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => {} // Do nothing.
+            }
 
-        match token.token_type {
-            TokenType::EOF => eprint!(" at end"),
-            TokenType::Error => {} // Nothing
-            _ => eprint!(" at '{}'", token.lexeme),
+            self.advance();
         }
+    }
+
+    fn error_at(&mut self, token: Token, message: &str) {
+        if self.panic_mode {
+            return;
+        }
+
+        self.panic_mode = true;
+
+        let near = match token.token_type {
+            TokenType::EOF => Some("end".to_owned()),
+            TokenType::Error => None,
+            _ => Some(format!("'{}'", token.lexeme)),
+        };
 
-        eprintln!(": {}", message);
-        self.had_error = true;
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message: message.to_owned(),
+            line: token.line,
+            column: token.column,
+            span: token.span,
+            near,
+            labels: Vec::new(),
+            notes: Vec::new(),
+        });
     }
 
     fn number(&mut self) {
         let result = self.previous.lexeme.parse::<f64>();
         match result {
-            Ok(value) => self.emit_constant(value),
-            Err(err) => {
-                println!(
-                    "Failed to parse number: {}. '{}'",
-                    err, self.previous.lexeme
-                );
-                panic!("Could not parse number.");
-            }
+            Ok(value) => self.emit_constant(Value::Number(value)),
+            Err(_) => self.error("Invalid number literal."),
         }
     }
 
+    fn string(&mut self) {
+        let lexeme = &self.previous.lexeme;
+        let string = self.heap.copy_string(&lexeme[1..lexeme.len() - 1]);
+        self.emit_constant(Value::Obj(string));
+    }
+
     fn emit_constant(&mut self, value: Value) {
-        let constant = self.chunk.add_constant(value);
-        if constant > u8::MAX {
+        let (line, column) = (self.previous.line, self.previous.column);
+        if !self.current_chunk().write_constant(value, line, column) {
             self.error("Too many constants in one chunk.");
+        }
+    }
+
+    fn make_constant(&mut self, value: Value) -> usize {
+        let constant = self.current_chunk().add_constant(value);
+        if constant > CONSTANT_LONG_MAX {
+            self.error("Too many constants in one chunk.");
+            return 0;
+        }
+
+        return constant;
+    }
+
+    fn call(&mut self) {
+        let arg_count = self.argument_list();
+        self.emit_bytes(OpCode::Call as u8, arg_count);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX as usize {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.".to_owned());
+        return arg_count as u8;
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.".to_owned());
+        let property = self.previous;
+        let name = self.identifier_constant(property);
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_indexed_at(&property, OpCode::SetProperty, name);
+        } else if self.match_token(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.emit_indexed_at(&property, OpCode::Invoke, name);
+            self.emit_bytes_at(&property, &[arg_count]);
+        } else {
+            self.emit_indexed(OpCode::GetProperty, name);
+        }
+    }
+
+    fn super_(&mut self) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            _ => {}
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.".to_owned());
+        self.consume(TokenType::Identifier, "Expect superclass method name.".to_owned());
+        let name = self.identifier_constant(self.previous);
+
+        self.named_variable(synthetic_token("this"), false);
+        if self.match_token(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable(synthetic_token("super"), false);
+            self.emit_indexed(OpCode::SuperInvoke, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable(synthetic_token("super"), false);
+            self.emit_indexed(OpCode::GetSuper, name);
+        }
+    }
+
+    fn this(&mut self) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
             return;
         }
 
-        self.emit_bytes(OpCode::Constant as u8, constant);
+        self.variable(false);
+    }
+
+    fn and(&mut self) {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit_byte(OpCode::Pop as u8);
+        self.parse_precendence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::Pop as u8);
+
+        self.parse_precendence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        self.named_variable(self.previous, can_assign);
+    }
+
+    fn named_variable(&mut self, name: Token, can_assign: bool) {
+        let local = self.compiler.resolve_local(&name);
+        let (get_op, set_op, arg) = match local {
+            Ok(Some(slot)) => (OpCode::GetLocal, OpCode::SetLocal, slot as usize),
+            Ok(None) => match self.compiler.resolve_upvalue(&name) {
+                Ok(Some(index)) => (OpCode::GetUpvalue, OpCode::SetUpvalue, index as usize),
+                Ok(None) => (
+                    OpCode::GetGlobal,
+                    OpCode::SetGlobal,
+                    self.identifier_constant(name),
+                ),
+                Err(message) => {
+                    self.error(message);
+                    return;
+                }
+            },
+            Err(message) => {
+                self.error(message);
+                return;
+            }
+        };
+
+        // Only globals have a constant operand; local and upvalue slots
+        // always fit in a byte.
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_indexed(set_op, arg);
+        } else {
+            self.emit_indexed(get_op, arg);
+        }
+    }
+
+    fn literal(&mut self) {
+        match self.previous.token_type {
+            TokenType::False => self.emit_byte(OpCode::False as u8),
+            TokenType::Nil => self.emit_byte(OpCode::Nil as u8),
+            TokenType::True => self.emit_byte(OpCode::True as u8),
+            _ => panic!("Unrecognized literal!"),
+        }
     }
 
     fn grouping(&mut self) {
@@ -154,86 +999,111 @@ impl<'a> Parser<'a> {
     }
 
     fn unary(&mut self) {
-        let operator_type = self.previous.token_type.clone();
+        let operator = self.previous;
 
         // Compile the operand.
         self.parse_precendence(Precedence::Unary);
 
         // Emit the operator instruction.
-        match operator_type {
-            TokenType::Minus => self.emit_byte(OpCode::Negate as u8),
+        match operator.token_type {
+            TokenType::Bang => self.emit_bytes_at(&operator, &[OpCode::Not as u8]),
+            TokenType::Minus => self.emit_bytes_at(&operator, &[OpCode::Negate as u8]),
             _ => panic!("Unrecognized unary operator!"),
         }
     }
 
     fn binary(&mut self) {
-
-        let operator_type = self.previous.token_type.clone();
-        let rule = get_rule(operator_type.clone());
+        let operator = self.previous;
+        let rule = get_rule(operator.token_type);
         self.parse_precendence(Precedence::from_u8((rule.precedence as u8) + 1).unwrap());
 
-        match operator_type {
-            TokenType::Plus => self.emit_byte(OpCode::Add as u8),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract as u8),
-            TokenType::Star => self.emit_byte(OpCode::Multiply as u8),
-            TokenType::Slash => self.emit_byte(OpCode::Divide as u8),
+        let bytes: &[u8] = match operator.token_type {
+            TokenType::BangEqual => &[OpCode::Equal as u8, OpCode::Not as u8],
+            TokenType::EqualEqual => &[OpCode::Equal as u8],
+            TokenType::Greater => &[OpCode::Greater as u8],
+            TokenType::GreaterEqual => &[OpCode::Less as u8, OpCode::Not as u8],
+            TokenType::Less => &[OpCode::Less as u8],
+            TokenType::LessEqual => &[OpCode::Greater as u8, OpCode::Not as u8],
+            TokenType::Plus => &[OpCode::Add as u8],
+            TokenType::Minus => &[OpCode::Subtract as u8],
+            TokenType::Star => &[OpCode::Multiply as u8],
+            TokenType::Slash => &[OpCode::Divide as u8],
             _ => panic!("Unrecognized binary operator!"),
-        }
+        };
+        self.emit_bytes_at(&operator, bytes);
     }
 
     fn parse_precendence(&mut self, precedence: Precedence) {
         self.advance();
 
-        let prefix = get_rule(self.previous.token_type.clone()).prefix;
+        let precedence_order = precedence as u8;
+        let can_assign = precedence_order <= Precedence::Assignment as u8;
+
+        let prefix = get_rule(self.previous.token_type).prefix;
         match prefix {
-            Some(f) => f(self),
+            Some(f) => f(self, can_assign),
             None => {
                 self.error("Expect expression.");
                 return;
             }
         }
 
-        let precedence_order = precedence as u8;
-
-        while precedence_order <= (get_rule(self.current.token_type.clone()).precedence as u8) {
+        while precedence_order <= (get_rule(self.current.token_type).precedence as u8) {
             self.advance();
 
-            let infix = get_rule(self.previous.token_type.clone()).infix.unwrap();
-            infix(self);
+            let infix = get_rule(self.previous.token_type).infix.unwrap();
+            infix(self, can_assign);
+        }
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.error("Invalid assignment target.");
         }
     }
 }
 
-pub fn compile(source: &Vec<char>) -> Option<Chunk> {
-    let mut parser = Parser::new(source);
+/// Compiles `source` into a function holding the top-level script, tagging
+/// every compiled function with `script`. Bytecode dumps, if enabled, are
+/// written to `debug_output`.
+pub fn compile(
+    source: &str,
+    heap: &mut Heap,
+    options: CompileOptions,
+    script: u32,
+    debug_output: &mut dyn Write,
+) -> Result<ObjRef, Vec<Diagnostic>> {
+    let mut parser = Parser::new(source, heap, options, script, debug_output);
 
     parser.advance();
-    if parser.had_error {
-        return None;
-    }
 
-    parser.expression();
+    while !parser.match_token(TokenType::EOF) {
+        parser.declaration();
+    }
 
-    parser.consume(TokenType::EOF, "Expect end of expression.".to_owned());
-    parser.end_compiler();
+    let (function, _) = parser.end_compiler();
 
-    if parser.had_error {
-        return None;
+    if !parser.diagnostics.is_empty() {
+        return Err(parser.diagnostics);
     } else {
-        println!("code: ");
-        disassemble_chunk(&parser.chunk);
-        println!("----------------");
-
-        return Some(parser.chunk);
+        return Ok(parser.heap.allocate(ObjKind::Function(function)));
     }
 }
 
+fn synthetic_token(text: &'static str) -> Token<'static> {
+    return Token {
+        token_type: TokenType::Identifier,
+        lexeme: text,
+        line: 0,
+        column: 0,
+        span: Span::default(),
+    };
+}
+
 fn get_rule(token_type: TokenType) -> ParseRule {
     match token_type {
         TokenType::LeftParen => ParseRule {
-            prefix: Some(|p| p.grouping()),
-            infix: None,
-            precedence: Precedence::None,
+            prefix: Some(|p, _| p.grouping()),
+            infix: Some(|p, _| p.call()),
+            precedence: Precedence::Call,
         },
         TokenType::RightParen => ParseRule {
             prefix: None,
@@ -257,17 +1127,17 @@ fn get_rule(token_type: TokenType) -> ParseRule {
         },
         TokenType::Dot => ParseRule {
             prefix: None,
-            infix: None,
-            precedence: Precedence::None,
+            infix: Some(|p, can_assign| p.dot(can_assign)),
+            precedence: Precedence::Call,
         },
         TokenType::Minus => ParseRule {
-            prefix: Some(|p| p.unary()),
-            infix: Some(|p| p.binary()),
+            prefix: Some(|p, _| p.unary()),
+            infix: Some(|p, _| p.binary()),
             precedence: Precedence::Term,
         },
         TokenType::Plus => ParseRule {
             prefix: None,
-            infix: Some(|p| p.binary()),
+            infix: Some(|p, _| p.binary()),
             precedence: Precedence::Term,
         },
         TokenType::Semicolon => ParseRule {
@@ -277,27 +1147,23 @@ fn get_rule(token_type: TokenType) -> ParseRule {
         },
         TokenType::Slash => ParseRule {
             prefix: None,
-            infix: Some(|p| {
-                p.binary();
-            }),
+            infix: Some(|p, _| p.binary()),
             precedence: Precedence::Factor,
         },
         TokenType::Star => ParseRule {
             prefix: None,
-            infix: Some(|p| {
-                p.binary();
-            }),
+            infix: Some(|p, _| p.binary()),
             precedence: Precedence::Factor,
         },
         TokenType::Bang => ParseRule {
-            prefix: None,
+            prefix: Some(|p, _| p.unary()),
             infix: None,
             precedence: Precedence::None,
         },
         TokenType::BangEqual => ParseRule {
             prefix: None,
-            infix: None,
-            precedence: Precedence::None,
+            infix: Some(|p, _| p.binary()),
+            precedence: Precedence::Equality,
         },
         TokenType::Equal => ParseRule {
             prefix: None,
@@ -306,48 +1172,48 @@ fn get_rule(token_type: TokenType) -> ParseRule {
         },
         TokenType::EqualEqual => ParseRule {
             prefix: None,
-            infix: None,
-            precedence: Precedence::None,
+            infix: Some(|p, _| p.binary()),
+            precedence: Precedence::Equality,
         },
         TokenType::Greater => ParseRule {
             prefix: None,
-            infix: None,
-            precedence: Precedence::None,
+            infix: Some(|p, _| p.binary()),
+            precedence: Precedence::Comparison,
         },
         TokenType::GreaterEqual => ParseRule {
             prefix: None,
-            infix: None,
-            precedence: Precedence::None,
+            infix: Some(|p, _| p.binary()),
+            precedence: Precedence::Comparison,
         },
         TokenType::Less => ParseRule {
             prefix: None,
-            infix: None,
-            precedence: Precedence::None,
+            infix: Some(|p, _| p.binary()),
+            precedence: Precedence::Comparison,
         },
         TokenType::LessEqual => ParseRule {
             prefix: None,
-            infix: None,
-            precedence: Precedence::None,
+            infix: Some(|p, _| p.binary()),
+            precedence: Precedence::Comparison,
         },
         TokenType::Identifier => ParseRule {
-            prefix: None,
+            prefix: Some(|p, can_assign| p.variable(can_assign)),
             infix: None,
             precedence: Precedence::None,
         },
         TokenType::String => ParseRule {
-            prefix: None,
+            prefix: Some(|p, _| p.string()),
             infix: None,
             precedence: Precedence::None,
         },
         TokenType::Number => ParseRule {
-            prefix: Some(|p| p.number()),
+            prefix: Some(|p, _| p.number()),
             infix: None,
             precedence: Precedence::None,
         },
         TokenType::And => ParseRule {
             prefix: None,
-            infix: None,
-            precedence: Precedence::None,
+            infix: Some(|p, _| p.and()),
+            precedence: Precedence::And,
         },
         TokenType::Class => ParseRule {
             prefix: None,
@@ -360,7 +1226,7 @@ fn get_rule(token_type: TokenType) -> ParseRule {
             precedence: Precedence::None,
         },
         TokenType::False => ParseRule {
-            prefix: None,
+            prefix: Some(|p, _| p.literal()),
             infix: None,
             precedence: Precedence::None,
         },
@@ -380,14 +1246,14 @@ fn get_rule(token_type: TokenType) -> ParseRule {
             precedence: Precedence::None,
         },
         TokenType::Nil => ParseRule {
-            prefix: None,
+            prefix: Some(|p, _| p.literal()),
             infix: None,
             precedence: Precedence::None,
         },
         TokenType::Or => ParseRule {
             prefix: None,
-            infix: None,
-            precedence: Precedence::None,
+            infix: Some(|p, _| p.or()),
+            precedence: Precedence::Or,
         },
         TokenType::Print => ParseRule {
             prefix: None,
@@ -400,17 +1266,17 @@ fn get_rule(token_type: TokenType) -> ParseRule {
             precedence: Precedence::None,
         },
         TokenType::Super => ParseRule {
-            prefix: None,
+            prefix: Some(|p, _| p.super_()),
             infix: None,
             precedence: Precedence::None,
         },
         TokenType::This => ParseRule {
-            prefix: None,
+            prefix: Some(|p, _| p.this()),
             infix: None,
             precedence: Precedence::None,
         },
         TokenType::True => ParseRule {
-            prefix: None,
+            prefix: Some(|p, _| p.literal()),
             infix: None,
             precedence: Precedence::None,
         },
@@ -436,3 +1302,57 @@ fn get_rule(token_type: TokenType) -> ParseRule {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    /// Compiles `source` and returns the line and message of each error.
+    fn errors(source: &str) -> Vec<(i32, String)> {
+        let mut heap = Heap::new();
+        match compile(source, &mut heap, CompileOptions::default(), 1, &mut io::sink()) {
+            Ok(_) => return Vec::new(),
+            Err(diagnostics) => {
+                return diagnostics
+                    .into_iter()
+                    .map(|diagnostic| (diagnostic.line, diagnostic.message))
+                    .collect();
+            }
+        }
+    }
+
+    #[test]
+    fn reports_an_error_in_every_statement() {
+        let source = "var = 1;\nprint 2;\nprint 3 +;\nvar ok = ;\nprint ok;\n";
+
+        assert_eq!(
+            errors(source),
+            [
+                (1, "Expect variable name.".to_owned()),
+                (3, "Expect expression.".to_owned()),
+                (4, "Expect expression.".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn synchronizes_inside_function_bodies() {
+        let source = "fun f() {\n  return 1 +;\n  print;\n}\nprint f(;\n";
+
+        assert_eq!(
+            errors(source),
+            [
+                (2, "Expect expression.".to_owned()),
+                (3, "Expect expression.".to_owned()),
+                (5, "Expect expression.".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn compiles_valid_code_without_errors() {
+        assert!(errors("var a = 1;\nfun f(b) { return a + b; }\nprint f(2);\n").is_empty());
+    }
+}
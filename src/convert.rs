@@ -0,0 +1,1123 @@
+//! Conversions between script values and Rust data through serde.
+//!
+//! [`to_value`] turns any `Serialize` type into a script value and
+//! [`from_value`] reads one back into any `Deserialize` type. Both go through
+//! a [`Handle`], so the value stays alive for as long as the host needs it:
+//!
+//! - `nil`, booleans, numbers and strings map to their Rust counterparts.
+//!   Integers are stored as numbers, so very large ones lose precision.
+//! - Structs become instances of a class named after the struct, with one
+//!   field per struct field. Maps become instances of a class named `Map`,
+//!   and their keys must be strings, numbers or booleans. Field names are
+//!   always strings, so numbers and booleans are parsed back out of them.
+//! - Lox has no list type, so sequences become [`List`] foreign objects.
+//! - Unit enum variants become strings. Other variants become an instance of
+//!   a class named after the enum, with a single field named after the
+//!   variant holding its contents.
+
+use std::{cell::RefCell, collections::HashMap, fmt};
+
+use serde::{
+    de::{
+        self,
+        value::{MapDeserializer, SeqDeserializer},
+        DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, Unexpected, VariantAccess,
+        Visitor,
+    },
+    forward_to_deserialize_any,
+    ser::{self, Serialize},
+};
+
+use crate::{
+    foreign::{ForeignObject, Tracer},
+    handle::Handle,
+    object::{ObjClass, ObjInstance, ObjKind, ObjRef},
+    value::Value,
+    vm::{RuntimeError, VM},
+};
+
+/// Why a value could not be converted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// How deeply `from_value` follows instances and lists before giving up. This
+/// also stops it at values that contain themselves.
+const MAX_DEPTH: usize = 128;
+
+thread_local! {
+    // Lists being printed, so that one containing itself prints as `[...]`
+    // instead of recursing forever.
+    static PRINTING: RefCell<Vec<*const List>> = const { RefCell::new(Vec::new()) };
+}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        return Error(message.to_string());
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        return Error(message.to_string());
+    }
+}
+
+/// A growable list of values, standing in for sequences.
+///
+/// Scripts can read its `length` and call `get(index)`, `set(index, value)`
+/// and `push(value)`. Register it with [`define_list_class`] to let scripts
+/// create their own with `List()`.
+pub struct List {
+    pub items: RefCell<Vec<Value>>,
+}
+
+impl List {
+    pub fn new() -> List {
+        return List {
+            items: RefCell::new(Vec::new()),
+        };
+    }
+
+    fn index(&self, args: &[Value]) -> Result<usize, RuntimeError> {
+        let index = match args.first() {
+            Some(Value::Number(index)) => *index,
+            _ => return Err(RuntimeError::new("List index must be a number.")),
+        };
+
+        if index < 0.0 || index.fract() != 0.0 || index as usize >= self.items.borrow().len() {
+            return Err(RuntimeError::new("List index out of range."));
+        }
+
+        return Ok(index as usize);
+    }
+}
+
+impl Default for List {
+    fn default() -> Self {
+        return List::new();
+    }
+}
+
+fn check_arity(args: &[Value], arity: usize) -> Result<(), RuntimeError> {
+    if args.len() != arity {
+        let message = format!("Expected {} arguments but got {}.", arity, args.len());
+        return Err(RuntimeError::new(message));
+    }
+
+    return Ok(());
+}
+
+impl ForeignObject for List {
+    fn to_string(&self) -> String {
+        let list = self as *const List;
+        if PRINTING.with(|printing| printing.borrow().contains(&list)) {
+            return "[...]".to_owned();
+        }
+
+        PRINTING.with(|printing| printing.borrow_mut().push(list));
+        let items: Vec<String> = self
+            .items
+            .borrow()
+            .iter()
+            .map(|item| item.show().to_string())
+            .collect();
+        PRINTING.with(|printing| printing.borrow_mut().pop());
+
+        return format!("[{}]", items.join(", "));
+    }
+
+    fn get_property(&self, _vm: &mut VM, name: &str) -> Result<Option<Value>, RuntimeError> {
+        return match name {
+            "length" => Ok(Some(Value::Number(self.items.borrow().len() as f64))),
+            _ => Ok(None),
+        };
+    }
+
+    fn invoke(&self, vm: &mut VM, name: &str, args: &[Value]) -> Result<Value, RuntimeError> {
+        match name {
+            "get" => {
+                check_arity(args, 1)?;
+                let index = self.index(args)?;
+                let item = self.items.borrow()[index];
+                // SAFETY: the list traces its items, so they are alive.
+                return Ok(unsafe { vm.handle_unchecked(item) }.value());
+            }
+            "set" => {
+                check_arity(args, 2)?;
+                let index = self.index(args)?;
+                self.items.borrow_mut()[index] = args[1];
+                return Ok(args[1]);
+            }
+            "push" => {
+                check_arity(args, 1)?;
+                self.items.borrow_mut().push(args[0]);
+                return Ok(Value::Nil);
+            }
+            _ => return Err(RuntimeError::new(format!("Undefined property '{}'.", name))),
+        }
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        for item in self.items.borrow().iter() {
+            // SAFETY: items only ever come from scripts, and are traced
+            // from then on.
+            unsafe { tracer.mark(*item) };
+        }
+    }
+}
+
+/// Makes `List` available to scripts as a class taking no arguments.
+pub fn define_list_class(vm: &mut VM) {
+    vm.define_foreign_class("List", 0, |_, _| Ok(Box::new(List::new())));
+}
+
+/// Converts `value` into a script value, allocating its strings, instances
+/// and lists on the VM's heap.
+pub fn to_value<T: Serialize + ?Sized>(vm: &mut VM, value: &T) -> Result<Handle, Error> {
+    let mut serializer = Serializer {
+        vm,
+        classes: HashMap::new(),
+        roots: 0,
+    };
+
+    let result = value.serialize(&mut serializer);
+
+    // Only an error can leave partly built objects on the stack.
+    while serializer.roots > 0 {
+        serializer.unroot();
+    }
+
+    // SAFETY: the value was just created on this VM's heap, and nothing
+    // has collected garbage since it was unrooted.
+    return result.map(|value| unsafe { vm.handle_unchecked(value) });
+}
+
+/// Converts a script value into `T`. Fails on values nested more than 128
+/// levels deep, which includes any value that contains itself.
+pub fn from_value<T: DeserializeOwned>(value: &Handle) -> Result<T, Error> {
+    return T::deserialize(Deserializer {
+        value: value.value(),
+        depth: 0,
+    });
+}
+
+struct Serializer<'a> {
+    vm: &'a mut VM,
+    // Classes already created for each struct or enum name.
+    classes: HashMap<&'static str, ObjRef>,
+    // How many values this serializer has pushed onto the VM stack.
+    roots: usize,
+}
+
+impl Serializer<'_> {
+    /// Keeps `value` alive across allocations until the matching `unroot`.
+    fn root(&mut self, value: Value) {
+        self.vm.push(value);
+        self.roots += 1;
+    }
+
+    fn unroot(&mut self) {
+        self.vm.pop().ok();
+        self.roots -= 1;
+    }
+
+    fn string(&mut self, chars: &str) -> Value {
+        return Value::Obj(self.vm.take_string(chars.to_owned()));
+    }
+
+    fn class(&mut self, name: &'static str) -> ObjRef {
+        if let Some(class) = self.classes.get(name) {
+            return *class;
+        }
+
+        let class_name = self.vm.take_string(name.to_owned());
+        self.root(Value::Obj(class_name));
+        let class = self.vm.allocate(ObjKind::Class(ObjClass {
+            name: class_name,
+            methods: RefCell::new(HashMap::new()),
+        }));
+        self.unroot();
+
+        self.classes.insert(name, class);
+        return class;
+    }
+
+    /// Creates an empty instance of the class `name`. It stays rooted until
+    /// `finish` so that its fields can be filled in.
+    fn begin_instance(&mut self, name: &'static str) -> ObjRef {
+        let class = self.class(name);
+        self.root(Value::Obj(class));
+        let instance = self.vm.allocate(ObjKind::Instance(ObjInstance {
+            class,
+            fields: RefCell::new(HashMap::new()),
+        }));
+        self.unroot();
+
+        self.root(Value::Obj(instance));
+        return instance;
+    }
+
+    /// Creates an empty list, rooted until `finish`.
+    fn begin_list(&mut self, capacity: usize) -> ObjRef {
+        let list = self
+            .vm
+            .new_foreign(List {
+                items: RefCell::new(Vec::with_capacity(capacity)),
+            })
+            .value();
+        self.root(list);
+
+        return match list {
+            Value::Obj(list) => list,
+            _ => unreachable!("Foreign objects are always heap objects."),
+        };
+    }
+
+    fn finish(&mut self, object: ObjRef) -> Value {
+        self.unroot();
+        return Value::Obj(object);
+    }
+
+    fn set_field(&mut self, instance: ObjRef, name: &str, value: Value) {
+        self.root(value);
+        let name = self.vm.take_string(name.to_owned());
+        instance
+            .as_instance()
+            .unwrap()
+            .fields
+            .borrow_mut()
+            .insert(name, value);
+        self.unroot();
+    }
+
+    /// Wraps `value` as the single field `variant` of an instance of `name`.
+    fn variant(&mut self, name: &'static str, variant: &'static str, value: Value) -> Value {
+        self.root(value);
+        let instance = self.begin_instance(name);
+        self.set_field(instance, variant, value);
+        let instance = self.finish(instance);
+        self.unroot();
+        return instance;
+    }
+}
+
+impl<'s, 'a> ser::Serializer for &'s mut Serializer<'a> {
+    type Ok = Value;
+    type Error = Error;
+
+    type SerializeSeq = ListBuilder<'s, 'a>;
+    type SerializeTuple = ListBuilder<'s, 'a>;
+    type SerializeTupleStruct = ListBuilder<'s, 'a>;
+    type SerializeTupleVariant = ListBuilder<'s, 'a>;
+    type SerializeMap = InstanceBuilder<'s, 'a>;
+    type SerializeStruct = InstanceBuilder<'s, 'a>;
+    type SerializeStructVariant = InstanceBuilder<'s, 'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+        return Ok(Value::Bool(v));
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+        return Ok(Value::Number(v as f64));
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+        return Ok(Value::Number(v as f64));
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+        return Ok(Value::Number(v as f64));
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+        return Ok(Value::Number(v as f64));
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+        return Ok(Value::Number(v as f64));
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+        return Ok(Value::Number(v as f64));
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+        return Ok(Value::Number(v as f64));
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+        return Ok(Value::Number(v as f64));
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value, Error> {
+        return Ok(Value::Number(v as f64));
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value, Error> {
+        return Ok(Value::Number(v));
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value, Error> {
+        return Ok(self.string(v.encode_utf8(&mut [0; 4])));
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value, Error> {
+        return Ok(self.string(v));
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+        let list = self.begin_list(v.len());
+        list.downcast_foreign::<List>()
+            .unwrap()
+            .items
+            .borrow_mut()
+            .extend(v.iter().map(|byte| Value::Number(*byte as f64)));
+        return Ok(self.finish(list));
+    }
+
+    fn serialize_none(self) -> Result<Value, Error> {
+        return Ok(Value::Nil);
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, Error> {
+        return value.serialize(self);
+    }
+
+    fn serialize_unit(self) -> Result<Value, Error> {
+        return Ok(Value::Nil);
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+        return Ok(Value::Nil);
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Value, Error> {
+        return Ok(self.string(variant));
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        return value.serialize(self);
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        let value = value.serialize(&mut *self)?;
+        return Ok(self.variant(name, variant, value));
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<ListBuilder<'s, 'a>, Error> {
+        let list = self.begin_list(len.unwrap_or(0));
+        return Ok(ListBuilder {
+            serializer: self,
+            list,
+            variant: None,
+        });
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<ListBuilder<'s, 'a>, Error> {
+        return self.serialize_seq(Some(len));
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<ListBuilder<'s, 'a>, Error> {
+        return self.serialize_seq(Some(len));
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<ListBuilder<'s, 'a>, Error> {
+        let list = self.begin_list(len);
+        return Ok(ListBuilder {
+            serializer: self,
+            list,
+            variant: Some((name, variant)),
+        });
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<InstanceBuilder<'s, 'a>, Error> {
+        let instance = self.begin_instance("Map");
+        return Ok(InstanceBuilder {
+            serializer: self,
+            instance,
+            key: None,
+            variant: None,
+        });
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        _len: usize,
+    ) -> Result<InstanceBuilder<'s, 'a>, Error> {
+        let instance = self.begin_instance(name);
+        return Ok(InstanceBuilder {
+            serializer: self,
+            instance,
+            key: None,
+            variant: None,
+        });
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<InstanceBuilder<'s, 'a>, Error> {
+        let instance = self.begin_instance(variant);
+        return Ok(InstanceBuilder {
+            serializer: self,
+            instance,
+            key: None,
+            variant: Some((name, variant)),
+        });
+    }
+}
+
+struct ListBuilder<'s, 'a> {
+    serializer: &'s mut Serializer<'a>,
+    list: ObjRef,
+    // The enum and variant names for tuple variants.
+    variant: Option<(&'static str, &'static str)>,
+}
+
+impl ListBuilder<'_, '_> {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let value = value.serialize(&mut *self.serializer)?;
+        self.list
+            .downcast_foreign::<List>()
+            .unwrap()
+            .items
+            .borrow_mut()
+            .push(value);
+        return Ok(());
+    }
+
+    fn finish(self) -> Result<Value, Error> {
+        let list = self.serializer.finish(self.list);
+        return match self.variant {
+            Some((name, variant)) => Ok(self.serializer.variant(name, variant, list)),
+            None => Ok(list),
+        };
+    }
+}
+
+impl ser::SerializeSeq for ListBuilder<'_, '_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        return self.push(value);
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        return self.finish();
+    }
+}
+
+impl ser::SerializeTuple for ListBuilder<'_, '_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        return self.push(value);
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        return self.finish();
+    }
+}
+
+impl ser::SerializeTupleStruct for ListBuilder<'_, '_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        return self.push(value);
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        return self.finish();
+    }
+}
+
+impl ser::SerializeTupleVariant for ListBuilder<'_, '_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        return self.push(value);
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        return self.finish();
+    }
+}
+
+struct InstanceBuilder<'s, 'a> {
+    serializer: &'s mut Serializer<'a>,
+    instance: ObjRef,
+    // A map key waiting for its value. It is rooted while it waits.
+    key: Option<ObjRef>,
+    // The enum and variant names for struct variants.
+    variant: Option<(&'static str, &'static str)>,
+}
+
+impl InstanceBuilder<'_, '_> {
+    fn finish(self) -> Result<Value, Error> {
+        let instance = self.serializer.finish(self.instance);
+        return match self.variant {
+            Some((name, variant)) => Ok(self.serializer.variant(name, variant, instance)),
+            None => Ok(instance),
+        };
+    }
+}
+
+impl ser::SerializeMap for InstanceBuilder<'_, '_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        let key = match key.serialize(&mut *self.serializer)? {
+            Value::Obj(key) if key.as_string().is_some() => key,
+            key @ (Value::Number(_) | Value::Bool(_)) => {
+                self.serializer.vm.take_string(key.show().to_string())
+            }
+            _ => {
+                return Err(Error(
+                    "Map keys must be strings, numbers or booleans.".to_owned(),
+                ))
+            }
+        };
+
+        self.serializer.root(Value::Obj(key));
+        self.key = Some(key);
+        return Ok(());
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .expect("serialize_key is called before serialize_value");
+        let value = value.serialize(&mut *self.serializer)?;
+
+        self.instance
+            .as_instance()
+            .unwrap()
+            .fields
+            .borrow_mut()
+            .insert(key, value);
+        self.serializer.unroot();
+        return Ok(());
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        return self.finish();
+    }
+}
+
+impl ser::SerializeStruct for InstanceBuilder<'_, '_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        let value = value.serialize(&mut *self.serializer)?;
+        self.serializer.set_field(self.instance, key, value);
+        return Ok(());
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        return self.finish();
+    }
+}
+
+impl ser::SerializeStructVariant for InstanceBuilder<'_, '_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        let value = value.serialize(&mut *self.serializer)?;
+        self.serializer.set_field(self.instance, key, value);
+        return Ok(());
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        return self.finish();
+    }
+}
+
+struct Deserializer {
+    value: Value,
+    // How many instances and lists enclose `value`.
+    depth: usize,
+}
+
+impl Deserializer {
+    fn nested(&self, value: Value) -> Deserializer {
+        return Deserializer {
+            value,
+            depth: self.depth + 1,
+        };
+    }
+
+    fn check_depth(&self) -> Result<(), Error> {
+        if self.depth >= MAX_DEPTH {
+            let message = format!(
+                "Can't convert values nested more than {} levels deep.",
+                MAX_DEPTH
+            );
+            return Err(Error(message));
+        }
+
+        return Ok(());
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for Deserializer {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        return self;
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let object = match self.value {
+            Value::Nil => return visitor.visit_unit(),
+            Value::Bool(value) => return visitor.visit_bool(value),
+            // Let integer targets accept whole numbers.
+            Value::Number(value)
+                if value.fract() == 0.0 && value >= i64::MIN as f64 && value < i64::MAX as f64 =>
+            {
+                return visitor.visit_i64(value as i64)
+            }
+            Value::Number(value) => return visitor.visit_f64(value),
+            Value::Obj(object) => object,
+        };
+
+        if let Some(string) = object.as_string() {
+            return visitor.visit_str(&string.chars);
+        }
+
+        self.check_depth()?;
+
+        if let Some(instance) = object.as_instance() {
+            let fields: Vec<(KeyDeserializer, Deserializer)> = instance
+                .fields
+                .borrow()
+                .iter()
+                .map(|(name, value)| {
+                    let key = KeyDeserializer {
+                        key: name.as_string().unwrap().chars.clone(),
+                    };
+                    (key, self.nested(*value))
+                })
+                .collect();
+
+            let mut map = MapDeserializer::new(fields.into_iter());
+            let value = visitor.visit_map(&mut map)?;
+            map.end()?;
+            return Ok(value);
+        }
+
+        if let Some(list) = object.downcast_foreign::<List>() {
+            let items: Vec<Deserializer> = list
+                .items
+                .borrow()
+                .iter()
+                .map(|item| self.nested(*item))
+                .collect();
+
+            let mut seq = SeqDeserializer::new(items.into_iter());
+            let value = visitor.visit_seq(&mut seq)?;
+            seq.end()?;
+            return Ok(value);
+        }
+
+        return Err(Error(format!(
+            "Can't convert '{}' to Rust data.",
+            object.get()
+        )));
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        return match self.value {
+            Value::Nil => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        };
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        return visitor.visit_newtype_struct(self);
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.check_depth()?;
+
+        if let Value::Obj(object) = self.value {
+            if let Some(string) = object.as_string() {
+                return visitor.visit_enum(EnumDeserializer {
+                    variant: string.chars.clone(),
+                    value: self.nested(Value::Nil),
+                });
+            }
+
+            if let Some(instance) = object.as_instance() {
+                let fields = instance.fields.borrow();
+                if let (1, Some((variant, value))) = (fields.len(), fields.iter().next()) {
+                    return visitor.visit_enum(EnumDeserializer {
+                        variant: variant.get().to_string(),
+                        value: self.nested(*value),
+                    });
+                }
+            }
+        }
+
+        let message = "Expected a string or an instance with one field for an enum.";
+        return Err(Error(message.to_owned()));
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+/// Reads a field name as a map key. `to_value` turns number and boolean keys
+/// into strings, so those are parsed back when the key type asks for them.
+struct KeyDeserializer {
+    key: String,
+}
+
+macro_rules! deserialize_parsed_key {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                return match self.key.parse() {
+                    Ok(value) => visitor.$visit(value),
+                    Err(_) => Err(de::Error::invalid_value(Unexpected::Str(&self.key), &visitor)),
+                };
+            }
+        )*
+    };
+}
+
+impl<'de> IntoDeserializer<'de, Error> for KeyDeserializer {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        return self;
+    }
+}
+
+impl<'de> de::Deserializer<'de> for KeyDeserializer {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        return visitor.visit_string(self.key);
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        // Only unit variants can be keys, and they are stored by name.
+        return visitor.visit_enum(IntoDeserializer::<Error>::into_deserializer(self.key));
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        return visitor.visit_newtype_struct(self);
+    }
+
+    deserialize_parsed_key! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_i128 => visit_i128,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_u128 => visit_u128,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    forward_to_deserialize_any! {
+        str string bytes byte_buf option unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct EnumDeserializer {
+    variant: String,
+    value: Deserializer,
+}
+
+impl<'de> EnumAccess<'de> for EnumDeserializer {
+    type Error = Error;
+    type Variant = Deserializer;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Deserializer), Error> {
+        let variant =
+            seed.deserialize(IntoDeserializer::<Error>::into_deserializer(self.variant))?;
+        return Ok((variant, self.value));
+    }
+}
+
+impl<'de> VariantAccess<'de> for Deserializer {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        return match self.value {
+            Value::Nil => Ok(()),
+            _ => Err(Error("Expected a unit variant.".to_owned())),
+        };
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        return seed.deserialize(self);
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        return de::Deserializer::deserialize_seq(self, visitor);
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        return de::Deserializer::deserialize_map(self, visitor);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::vm::VMOptions;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Point {
+        x: f64,
+        y: f64,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Shape {
+        name: String,
+        closed: bool,
+        points: Vec<Point>,
+        label: Option<String>,
+        grid: Vec<Vec<i32>>,
+        tags: HashMap<String, u32>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Command {
+        Stop,
+        Move(f64),
+        Jump(i32, String),
+        Draw { shape: Shape, color: Option<u8> },
+    }
+
+    fn round_trip<T>(vm: &mut VM, value: &T) -> T
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let handle = to_value(vm, value).unwrap();
+        return from_value(&handle).unwrap();
+    }
+
+    fn shape(name: &str, points: usize) -> Shape {
+        return Shape {
+            name: name.to_owned(),
+            closed: points > 3,
+            points: (0..points)
+                .map(|i| Point {
+                    x: i as f64,
+                    y: -(i as f64) / 2.0,
+                })
+                .collect(),
+            label: None,
+            grid: vec![vec![1, 2], vec![], vec![3]],
+            tags: HashMap::from([("sides".to_owned(), points as u32)]),
+        };
+    }
+
+    #[test]
+    fn round_trips_structs_and_maps() {
+        let mut vm = VM::new();
+
+        let mut value = shape("triangle", 3);
+        value.label = Some("A".to_owned());
+        assert_eq!(round_trip(&mut vm, &value), value);
+
+        let map = HashMap::from([("one".to_owned(), 1.0), ("two".to_owned(), 2.0)]);
+        assert_eq!(round_trip(&mut vm, &map), map);
+    }
+
+    #[test]
+    fn round_trips_number_and_bool_keys() {
+        let mut vm = VM::new();
+
+        let numbers = HashMap::from([(1u32, 0.5), (20, -2.0)]);
+        assert_eq!(round_trip(&mut vm, &numbers), numbers);
+
+        let negative = HashMap::from([(-3i64, 'a'), (4, 'b')]);
+        assert_eq!(round_trip(&mut vm, &negative), negative);
+
+        let bools = HashMap::from([(true, "yes".to_owned()), (false, "no".to_owned())]);
+        assert_eq!(round_trip(&mut vm, &bools), bools);
+
+        let names = to_value(&mut vm, &HashMap::from([("one", 1)])).unwrap();
+        let error = from_value::<HashMap<u32, u32>>(&names).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid value: string \"one\", expected u32"
+        );
+    }
+
+    fn commands() -> Vec<Command> {
+        return vec![
+            Command::Stop,
+            Command::Move(1.5),
+            Command::Jump(-3, "up".to_owned()),
+            Command::Draw {
+                shape: shape("square", 4),
+                color: Some(7),
+            },
+        ];
+    }
+
+    #[test]
+    fn round_trips_enums() {
+        let mut vm = VM::new();
+
+        let commands = commands();
+        assert_eq!(round_trip(&mut vm, &commands), commands);
+    }
+
+    #[test]
+    fn round_trips_options_and_nested_lists() {
+        let mut vm = VM::new();
+
+        let values: Vec<Option<Vec<Vec<String>>>> = vec![
+            None,
+            Some(vec![]),
+            Some(vec![
+                vec!["a".to_owned()],
+                vec!["b".to_owned(), "c".to_owned()],
+            ]),
+        ];
+        assert_eq!(round_trip(&mut vm, &values), values);
+    }
+
+    #[test]
+    fn keeps_partial_results_alive_through_collections() {
+        let mut vm = VM::new();
+
+        // Well past the first collection threshold, so the heap collects
+        // while the result is still being built.
+        let shapes: Vec<Shape> = (0..2_000)
+            .map(|i| shape(&format!("shape {} {}", i, "x".repeat(500)), 10))
+            .collect();
+        let handle = to_value(&mut vm, &shapes).unwrap();
+
+        // Collect again now that only the handle holds the result.
+        vm.interpret("for (var i = 0; i < 20000; i = i + 1) { str(i); }");
+
+        let result: Vec<Shape> = from_value(&handle).unwrap();
+        assert_eq!(result, shapes);
+    }
+
+    #[test]
+    fn keeps_partial_results_alive_when_collecting_at_every_allocation() {
+        let mut vm = VM::with_options(VMOptions {
+            stress_gc: true,
+            ..Default::default()
+        });
+
+        let commands = commands();
+        assert_eq!(round_trip(&mut vm, &commands), commands);
+    }
+
+    #[test]
+    fn rejects_values_that_contain_themselves() {
+        let mut vm = VM::new();
+        define_list_class(&mut vm);
+        vm.interpret("var list = List(); list.push(list);");
+
+        let list = vm.get_global("list").unwrap();
+        assert_eq!(list.to_string(), "[[...]]");
+        assert!(from_value::<de::IgnoredAny>(&list).is_err());
+    }
+}
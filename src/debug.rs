@@ -1,21 +1,28 @@
 use num_traits::FromPrimitive;
 
-use crate::chunk::{OpCode, Chunk, Value};
+use std::io::{self, Write};
+
+use crate::{chunk::{OpCode, Chunk}, value::Value};
+
+pub fn disassemble_chunk(out: &mut dyn Write, chunk: &Chunk, name: &str) -> io::Result<()> {
+    writeln!(out, "== {} ==", name)?;
 
-pub fn disassemble_chunk(chunk: &Chunk) {
     let mut offset = 0;
     while offset < chunk.code.len() {
-        offset = disassemble_instruction(chunk, offset);
+        offset = disassemble_instruction(out, chunk, offset)?;
     }
+
+    return Ok(());
 }
 
-pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
-    print!("{:04} ", offset);
+pub fn disassemble_instruction(out: &mut dyn Write, chunk: &Chunk, offset: usize) -> io::Result<usize> {
+    write!(out, "{:04} ", offset)?;
 
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
-        print!("  | ");
+    let line = chunk.get_line(offset);
+    if offset > 0 && line == chunk.get_line(offset - 1) {
+        write!(out, "  | ")?;
     } else {
-        print!("{:<4}", chunk.lines[offset]);
+        write!(out, "{:<4}", line)?;
     }
 
     let instruction: u8 = chunk.code[offset];
@@ -24,35 +31,147 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
 
     match opcode {
         Some(value) => match value {
-            OpCode::Constant => constant_instruction("OP_CONSTANT", &chunk, offset),
-            OpCode::Add => simple_instruction("OP_ADD", offset),
-            OpCode::Subtract => simple_instruction("OP_SUBTRACT", offset),
-            OpCode::Multiply => simple_instruction("OP_MULTIPLY", offset),
-            OpCode::Divide => simple_instruction("OP_DIVIDE", offset),
-            OpCode::Negate => simple_instruction("OP_NEGATE", offset),
-            OpCode::Return => simple_instruction("OP_RETURN", offset),
+            OpCode::Constant => constant_instruction(out, "OP_CONSTANT", chunk, offset),
+            OpCode::ConstantLong => constant_long_instruction(out, "OP_CONSTANT_LONG", chunk, offset),
+            OpCode::Nil => simple_instruction(out, "OP_NIL", offset),
+            OpCode::True => simple_instruction(out, "OP_TRUE", offset),
+            OpCode::False => simple_instruction(out, "OP_FALSE", offset),
+            OpCode::Pop => simple_instruction(out, "OP_POP", offset),
+            OpCode::GetLocal => byte_instruction(out, "OP_GET_LOCAL", chunk, offset),
+            OpCode::SetLocal => byte_instruction(out, "OP_SET_LOCAL", chunk, offset),
+            OpCode::GetGlobal => constant_instruction(out, "OP_GET_GLOBAL", chunk, offset),
+            OpCode::GetGlobalLong => constant_long_instruction(out, "OP_GET_GLOBAL_LONG", chunk, offset),
+            OpCode::DefineGlobal => constant_instruction(out, "OP_DEFINE_GLOBAL", chunk, offset),
+            OpCode::DefineGlobalLong => constant_long_instruction(out, "OP_DEFINE_GLOBAL_LONG", chunk, offset),
+            OpCode::SetGlobal => constant_instruction(out, "OP_SET_GLOBAL", chunk, offset),
+            OpCode::SetGlobalLong => constant_long_instruction(out, "OP_SET_GLOBAL_LONG", chunk, offset),
+            OpCode::GetUpvalue => byte_instruction(out, "OP_GET_UPVALUE", chunk, offset),
+            OpCode::SetUpvalue => byte_instruction(out, "OP_SET_UPVALUE", chunk, offset),
+            OpCode::GetProperty => constant_instruction(out, "OP_GET_PROPERTY", chunk, offset),
+            OpCode::GetPropertyLong => constant_long_instruction(out, "OP_GET_PROPERTY_LONG", chunk, offset),
+            OpCode::SetProperty => constant_instruction(out, "OP_SET_PROPERTY", chunk, offset),
+            OpCode::SetPropertyLong => constant_long_instruction(out, "OP_SET_PROPERTY_LONG", chunk, offset),
+            OpCode::GetSuper => constant_instruction(out, "OP_GET_SUPER", chunk, offset),
+            OpCode::GetSuperLong => constant_long_instruction(out, "OP_GET_SUPER_LONG", chunk, offset),
+            OpCode::Equal => simple_instruction(out, "OP_EQUAL", offset),
+            OpCode::Greater => simple_instruction(out, "OP_GREATER", offset),
+            OpCode::Less => simple_instruction(out, "OP_LESS", offset),
+            OpCode::Add => simple_instruction(out, "OP_ADD", offset),
+            OpCode::Subtract => simple_instruction(out, "OP_SUBTRACT", offset),
+            OpCode::Multiply => simple_instruction(out, "OP_MULTIPLY", offset),
+            OpCode::Divide => simple_instruction(out, "OP_DIVIDE", offset),
+            OpCode::Not => simple_instruction(out, "OP_NOT", offset),
+            OpCode::Negate => simple_instruction(out, "OP_NEGATE", offset),
+            OpCode::Print => simple_instruction(out, "OP_PRINT", offset),
+            OpCode::Jump => jump_instruction(out, "OP_JUMP", 1, chunk, offset),
+            OpCode::JumpIfFalse => jump_instruction(out, "OP_JUMP_IF_FALSE", 1, chunk, offset),
+            OpCode::Loop => jump_instruction(out, "OP_LOOP", -1, chunk, offset),
+            OpCode::Call => byte_instruction(out, "OP_CALL", chunk, offset),
+            OpCode::Invoke => invoke_instruction(out, "OP_INVOKE", false, chunk, offset),
+            OpCode::InvokeLong => invoke_instruction(out, "OP_INVOKE_LONG", true, chunk, offset),
+            OpCode::SuperInvoke => invoke_instruction(out, "OP_SUPER_INVOKE", false, chunk, offset),
+            OpCode::SuperInvokeLong => invoke_instruction(out, "OP_SUPER_INVOKE_LONG", true, chunk, offset),
+            OpCode::Closure => closure_instruction(out, "OP_CLOSURE", false, chunk, offset),
+            OpCode::ClosureLong => closure_instruction(out, "OP_CLOSURE_LONG", true, chunk, offset),
+            OpCode::CloseUpvalue => simple_instruction(out, "OP_CLOSE_UPVALUE", offset),
+            OpCode::Return => simple_instruction(out, "OP_RETURN", offset),
+            OpCode::Class => constant_instruction(out, "OP_CLASS", chunk, offset),
+            OpCode::ClassLong => constant_long_instruction(out, "OP_CLASS_LONG", chunk, offset),
+            OpCode::Inherit => simple_instruction(out, "OP_INHERIT", offset),
+            OpCode::Method => constant_instruction(out, "OP_METHOD", chunk, offset),
+            OpCode::MethodLong => constant_long_instruction(out, "OP_METHOD_LONG", chunk, offset),
         },
         None => {
-            println!("Unknown opcode {}", instruction);
-            return offset + 1;
+            writeln!(out, "Unknown opcode {}", instruction)?;
+            return Ok(offset + 1);
         }
     }
 }
 
-pub fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+pub fn constant_instruction(out: &mut dyn Write, name: &str, chunk: &Chunk, offset: usize) -> io::Result<usize> {
     let constant = chunk.code[offset + 1] as usize;
-    print!("{:<16} {:>4} '", name, constant);
-    print_value(chunk.constants[constant]);
-    println!();
-    return offset + 2;
+    write!(out, "{:<16} {:>4} '", name, constant)?;
+    print_value(out, chunk.constants[constant])?;
+    writeln!(out)?;
+    return Ok(offset + 2);
+}
+
+pub fn invoke_instruction(out: &mut dyn Write, name: &str, long: bool, chunk: &Chunk, offset: usize) -> io::Result<usize> {
+    let (constant, offset) = read_index(chunk, offset + 1, long);
+    let arg_count = chunk.code[offset];
+    write!(out, "{:<16} ({} args) {:>4} '", name, arg_count, constant)?;
+    print_value(out, chunk.constants[constant])?;
+    writeln!(out)?;
+    return Ok(offset + 1);
+}
+
+pub fn closure_instruction(out: &mut dyn Write, name: &str, long: bool, chunk: &Chunk, offset: usize) -> io::Result<usize> {
+    let (constant, mut offset) = read_index(chunk, offset + 1, long);
+
+    write!(out, "{:<16} {:>4} ", name, constant)?;
+    print_value(out, chunk.constants[constant])?;
+    writeln!(out)?;
+
+    let upvalue_count = match chunk.constants[constant] {
+        Value::Obj(obj) => obj.as_function().map_or(0, |function| function.upvalue_count),
+        _ => 0,
+    };
+
+    for _ in 0..upvalue_count {
+        let is_local = chunk.code[offset];
+        let index = chunk.code[offset + 1];
+        writeln!(out,
+            "{:04}      |                     {} {}",
+            offset,
+            if is_local == 1 { "local" } else { "upvalue" },
+            index
+        )?;
+        offset += 2;
+    }
+
+    return Ok(offset);
+}
+
+pub fn byte_instruction(out: &mut dyn Write, name: &str, chunk: &Chunk, offset: usize) -> io::Result<usize> {
+    let slot = chunk.code[offset + 1];
+    writeln!(out, "{:<16} {:>4}", name, slot)?;
+    return Ok(offset + 2);
+}
+
+pub fn jump_instruction(out: &mut dyn Write, name: &str, sign: i32, chunk: &Chunk, offset: usize) -> io::Result<usize> {
+    let jump = ((chunk.code[offset + 1] as u16) << 8) | chunk.code[offset + 2] as u16;
+    let target = offset as i32 + 3 + sign * jump as i32;
+    writeln!(out, "{:<16} {:>4} -> {}", name, offset, target)?;
+    return Ok(offset + 3);
+}
+
+pub fn constant_long_instruction(out: &mut dyn Write, name: &str, chunk: &Chunk, offset: usize) -> io::Result<usize> {
+    let (constant, offset) = read_index(chunk, offset + 1, true);
+    write!(out, "{:<16} {:>4} '", name, constant)?;
+    print_value(out, chunk.constants[constant])?;
+    writeln!(out)?;
+    return Ok(offset);
+}
+
+/// Decodes the constant index at `offset`, returning it along with the offset
+/// just past it.
+fn read_index(chunk: &Chunk, offset: usize, long: bool) -> (usize, usize) {
+    if !long {
+        return (chunk.code[offset] as usize, offset + 1);
+    }
+
+    let constant = ((chunk.code[offset] as usize) << 16)
+        | ((chunk.code[offset + 1] as usize) << 8)
+        | chunk.code[offset + 2] as usize;
+    return (constant, offset + 3);
 }
 
-pub fn print_value(value: Value) {
-    print!("{}", value);
+pub fn print_value(out: &mut dyn Write, value: Value) -> io::Result<()> {
+    return write!(out, "{}", value.show());
 }
 
 
-pub fn simple_instruction(name: &str, offset: usize) -> usize {
-    println!("{}", name);
-    return offset + 1;
+pub fn simple_instruction(out: &mut dyn Write, name: &str, offset: usize) -> io::Result<usize> {
+    writeln!(out, "{}", name)?;
+    return Ok(offset + 1);
 }
\ No newline at end of file
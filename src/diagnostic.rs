@@ -0,0 +1,313 @@
+use std::{
+    fmt,
+    io::{self, IsTerminal},
+};
+
+use crate::scanner::{Span, Token};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found while compiling or running a script, positioned at the
+/// token that caused it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub line: i32,
+    pub column: i32,
+    /// Byte range of the offending token in the source. Empty when only the
+    /// position is known, as for runtime errors.
+    pub span: Span,
+    /// How to describe the token in the header, e.g. `'x'` or `end`. `None`
+    /// for errors reported by the scanner, whose token has no useful text.
+    pub near: Option<String>,
+    /// Other places in the source that help explain the error.
+    pub labels: Vec<Label>,
+    /// Free-form lines printed after the source snippets.
+    pub notes: Vec<String>,
+}
+
+/// A secondary position attached to a diagnostic.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub message: String,
+    pub line: i32,
+    pub column: i32,
+    pub span: Span,
+}
+
+impl Label {
+    pub(crate) fn at(token: &Token, message: &str) -> Label {
+        return Label {
+            message: message.to_owned(),
+            line: token.line,
+            column: token.column,
+            span: token.span,
+        };
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+            Severity::Warning => write!(f, "Warning"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line >= 1 {
+            write!(f, "[line {}:{}] ", self.line, self.column)?;
+        }
+
+        write!(f, "{}", self.severity)?;
+
+        if let Some(near) = &self.near {
+            write!(f, " at {}", near)?;
+        }
+
+        return write!(f, ": {}", self.message);
+    }
+}
+
+/// Something to underline in a source line.
+struct Mark<'a> {
+    line: i32,
+    column: i32,
+    span: Span,
+    marker: char,
+    color: &'static str,
+    message: Option<&'a str>,
+}
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+
+/// Formats diagnostics rustc-style: a header, the offending source line with
+/// the token underlined, then any labels and notes.
+pub struct Renderer<'a> {
+    file: &'a str,
+    source: &'a str,
+    /// Whether to emit ANSI colour codes. Defaults to whether stderr is a
+    /// terminal.
+    pub color: bool,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(file: &'a str, source: &'a str) -> Renderer<'a> {
+        return Renderer {
+            file,
+            source,
+            color: io::stderr().is_terminal(),
+        };
+    }
+
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        let mut out = String::new();
+
+        let (severity, accent) = match diagnostic.severity {
+            Severity::Error => ("error", RED),
+            Severity::Warning => ("warning", YELLOW),
+        };
+
+        let near = match &diagnostic.near {
+            Some(near) => format!(" at {}", near),
+            None => String::new(),
+        };
+
+        out.push_str(&format!(
+            "{}{}",
+            self.paint(accent, severity),
+            self.paint(BOLD, &format!("{}: {}", near, diagnostic.message))
+        ));
+        out.push('\n');
+
+        let max_line = diagnostic
+            .labels
+            .iter()
+            .map(|label| label.line)
+            .fold(diagnostic.line, i32::max);
+        let gutter = " ".repeat(max_line.max(1).to_string().len());
+
+        // Errors without a position, such as runtime errors in code from
+        // another source, get no location or snippet.
+        if diagnostic.line >= 1 {
+            out.push_str(&format!(
+                "{}{} {}:{}:{}\n",
+                gutter,
+                self.paint(BLUE, "-->"),
+                self.file,
+                diagnostic.line,
+                diagnostic.column
+            ));
+            out.push_str(&format!("{} {}\n", gutter, self.paint(BLUE, "|")));
+        }
+
+        let mut marks = vec![Mark {
+            line: diagnostic.line,
+            column: diagnostic.column,
+            span: diagnostic.span,
+            marker: '^',
+            color: accent,
+            message: None,
+        }];
+
+        for label in &diagnostic.labels {
+            marks.push(Mark {
+                line: label.line,
+                column: label.column,
+                span: label.span,
+                marker: '-',
+                color: BLUE,
+                message: Some(&label.message),
+            });
+        }
+
+        // Show each line once, in source order, with all of its marks.
+        let mut lines: Vec<i32> = marks.iter().map(|mark| mark.line).collect();
+        lines.sort();
+        lines.dedup();
+
+        for line in lines {
+            let on_line: Vec<&Mark> = marks.iter().filter(|mark| mark.line == line).collect();
+            self.snippet(&mut out, &gutter, line, &on_line);
+        }
+
+        if !diagnostic.notes.is_empty() {
+            out.push_str(&format!("{} {}\n", gutter, self.paint(BLUE, "|")));
+        }
+
+        for note in &diagnostic.notes {
+            out.push_str(&format!(
+                "{} {} note: {}\n",
+                gutter,
+                self.paint(BLUE, "="),
+                note
+            ));
+        }
+
+        return out;
+    }
+
+    /// Writes one source line followed by rows underlining `marks` on it.
+    fn snippet(&self, out: &mut String, gutter: &str, line: i32, marks: &[&Mark]) {
+        // Synthetic tokens have no position in the source.
+        if line < 1 {
+            return;
+        }
+
+        // Errors at the end of the file may sit on a line past the last one.
+        let text = self.source.lines().nth(line as usize - 1).unwrap_or("");
+        let chars: Vec<char> = text.chars().collect();
+        let number = line.to_string();
+
+        out.push_str(&format!(
+            "{}{} {} {}\n",
+            self.paint(BLUE, &number),
+            &gutter[number.len().min(gutter.len())..],
+            self.paint(BLUE, "|"),
+            text
+        ));
+
+        // (start, width, mark), with start as a 0-based char index.
+        let mut placed: Vec<(usize, usize, &Mark)> = marks
+            .iter()
+            .filter(|mark| mark.column >= 1)
+            .map(|mark| {
+                let start = mark.column as usize - 1;
+                let width = self
+                    .source
+                    .get(mark.span.start..mark.span.end)
+                    .map(|lexeme| lexeme.chars().count())
+                    .unwrap_or(0)
+                    .min(chars.len().saturating_sub(start))
+                    .max(1);
+                (start, width, *mark)
+            })
+            .collect();
+        placed.sort_by_key(|(start, _, _)| *start);
+
+        let Some(&(_, _, last)) = placed.last() else {
+            return;
+        };
+
+        let mut row = String::new();
+        let mut column = 0;
+        for (start, width, mark) in &placed {
+            // Overlapping marks would garble the row, so keep the first.
+            if *start < column {
+                continue;
+            }
+
+            row.push_str(&pad(&chars, column, *start));
+            row.push_str(&self.paint(mark.color, &mark.marker.to_string().repeat(*width)));
+            column = start + width;
+        }
+
+        // The rightmost message fits on the underline row itself.
+        if let Some(message) = last.message {
+            row.push(' ');
+            row.push_str(&self.paint(last.color, message));
+        }
+
+        self.gutter_row(out, gutter, &row);
+
+        // Any others hang below their marks, rightmost first.
+        let hanging: Vec<&(usize, usize, &Mark)> = placed[..placed.len() - 1]
+            .iter()
+            .filter(|(_, _, mark)| mark.message.is_some())
+            .collect();
+
+        for i in (0..hanging.len()).rev() {
+            let mut connector = String::new();
+            let mut text_row = String::new();
+            let mut column = 0;
+
+            for (j, (start, _, mark)) in hanging[..=i].iter().enumerate() {
+                connector.push_str(&pad(&chars, column, *start));
+                connector.push_str(&self.paint(mark.color, "|"));
+
+                text_row.push_str(&pad(&chars, column, *start));
+                if j == i {
+                    text_row.push_str(&self.paint(mark.color, mark.message.unwrap_or("")));
+                } else {
+                    text_row.push_str(&self.paint(mark.color, "|"));
+                }
+
+                column = start + 1;
+            }
+
+            self.gutter_row(out, gutter, &connector);
+            self.gutter_row(out, gutter, &text_row);
+        }
+    }
+
+    fn gutter_row(&self, out: &mut String, gutter: &str, row: &str) {
+        out.push_str(&format!("{} {} {}\n", gutter, self.paint(BLUE, "|"), row));
+    }
+
+    fn paint(&self, color: &str, text: &str) -> String {
+        if self.color {
+            return format!("{}{}{}", color, text, RESET);
+        }
+
+        return text.to_owned();
+    }
+}
+
+/// Whitespace covering `chars[from..to]`, keeping tabs so that what follows
+/// lines up with the source line above it.
+fn pad(chars: &[char], from: usize, to: usize) -> String {
+    return (from..to)
+        .map(|i| if chars.get(i) == Some(&'\t') { '\t' } else { ' ' })
+        .collect();
+}
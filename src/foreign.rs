@@ -0,0 +1,69 @@
+use std::any::Any;
+
+use crate::{
+    memory::Heap,
+    value::Value,
+    vm::{RuntimeError, VM},
+};
+
+/// A Rust value exposed to scripts as an object.
+///
+/// Foreign objects are owned by the heap and shared between every script
+/// value that refers to them, so hooks only get `&self`. Use `Cell` or
+/// `RefCell` for state that scripts can change.
+///
+/// As with natives, an object returned from `get_property` or `invoke` must
+/// be an argument or come from a `Handle`. Values the object holds and traces
+/// can be handed out through `VM::handle_unchecked`.
+pub trait ForeignObject: Any {
+    /// How the object appears when printed or passed to `str`.
+    fn to_string(&self) -> String;
+
+    /// Looks up the property `name`. Returning `Ok(None)` treats `name` as a
+    /// method: `object.name` evaluates to a function that runs `invoke` when
+    /// called, which is where an unknown name gets reported.
+    fn get_property(&self, _vm: &mut VM, _name: &str) -> Result<Option<Value>, RuntimeError> {
+        return Ok(None);
+    }
+
+    /// Handles `object.name = value`. Objects are read-only by default.
+    fn set_property(&self, _vm: &mut VM, name: &str, _value: Value) -> Result<(), RuntimeError> {
+        let message = format!("Can't set property '{}' on {}.", name, self.to_string());
+        return Err(RuntimeError::new(message));
+    }
+
+    /// Handles `object.name(args)`.
+    fn invoke(&self, _vm: &mut VM, name: &str, _args: &[Value]) -> Result<Value, RuntimeError> {
+        return Err(RuntimeError::new(format!("Undefined property '{}'.", name)));
+    }
+
+    /// Marks every `Value` the object holds on to, so that the garbage
+    /// collector keeps them alive. Objects that hold no values need not
+    /// implement this.
+    fn trace(&self, _tracer: &mut Tracer) {}
+}
+
+/// Creates a foreign object from the arguments a script called its class
+/// with.
+pub type ForeignConstructor = fn(&mut VM, &[Value]) -> Result<Box<dyn ForeignObject>, RuntimeError>;
+
+/// Handed to `ForeignObject::trace` during garbage collection.
+pub struct Tracer<'a> {
+    heap: &'a mut Heap,
+}
+
+impl<'a> Tracer<'a> {
+    pub(crate) fn new(heap: &'a mut Heap) -> Tracer<'a> {
+        return Tracer { heap };
+    }
+
+    /// Keeps `value` alive through this collection.
+    ///
+    /// # Safety
+    ///
+    /// `value` must belong to this VM and still be alive, which holds for
+    /// values the object got from scripts and has traced ever since.
+    pub unsafe fn mark(&mut self, value: Value) {
+        self.heap.mark_value(value);
+    }
+}
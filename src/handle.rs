@@ -0,0 +1,118 @@
+use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc};
+
+use crate::{foreign::ForeignObject, memory::Heap, object::ObjRef, value::Value};
+
+/// Objects that hosts hold handles to, with how many handles refer to each.
+/// The VM marks those with handles as roots whenever it collects garbage.
+///
+/// An object whose last handle is dropped keeps its entry, with a count of
+/// zero, until the next collection. It is certainly still alive until then,
+/// so the VM can accept it back from a native that returns
+/// `vm.new_string(...).value()`.
+#[derive(Default)]
+pub(crate) struct Roots(RefCell<HashMap<ObjRef, usize>>);
+
+impl Roots {
+    /// Whether no handles are left.
+    pub(crate) fn is_empty(&self) -> bool {
+        return self.0.borrow().values().all(|count| *count == 0);
+    }
+
+    /// Whether `object` has been held by a handle since the last collection.
+    pub(crate) fn contains(&self, object: ObjRef) -> bool {
+        return self.0.borrow().contains_key(&object);
+    }
+
+    pub(crate) fn mark(&self, heap: &mut Heap) {
+        for (object, count) in self.0.borrow().iter() {
+            if *count > 0 {
+                heap.mark_object(*object);
+            }
+        }
+    }
+
+    /// Forgets objects without handles, which the collection that just ran
+    /// may have freed.
+    pub(crate) fn sweep(&self) {
+        self.0.borrow_mut().retain(|_, count| *count > 0);
+    }
+
+    fn add(&self, value: Value) {
+        if let Value::Obj(object) = value {
+            *self.0.borrow_mut().entry(object).or_insert(0) += 1;
+        }
+    }
+
+    fn remove(&self, value: Value) {
+        if let Value::Obj(object) = value {
+            *self.0.borrow_mut().get_mut(&object).unwrap() -= 1;
+        }
+    }
+}
+
+/// A value the VM keeps alive for as long as the handle exists, so hosts can
+/// hold on to it across calls into the VM and read it safely. Returned by the
+/// VM's own accessors, or created with `VM::handle`.
+///
+/// A handle keeps its VM's heap alive too: dropping the VM while handles to
+/// its objects remain leaks the heap rather than freeing it.
+pub struct Handle {
+    value: Value,
+    roots: Rc<Roots>,
+}
+
+impl Handle {
+    pub(crate) fn new(value: Value, roots: &Rc<Roots>) -> Handle {
+        roots.add(value);
+        return Handle {
+            value,
+            roots: Rc::clone(roots),
+        };
+    }
+
+    /// Whether the handle came from the VM that owns `roots`.
+    pub(crate) fn belongs_to(&self, roots: &Rc<Roots>) -> bool {
+        return Rc::ptr_eq(&self.roots, roots);
+    }
+
+    /// The value itself, e.g. to pass as an argument to `VM::call` or return
+    /// from a native. It stays alive for as long as this handle does, and
+    /// until the next garbage collection after that.
+    pub fn value(&self) -> Value {
+        return self.value;
+    }
+
+    /// The contents of the value if it is a string.
+    pub fn as_str(&self) -> Option<&str> {
+        return unsafe { self.value.as_str() };
+    }
+
+    /// The value as a foreign object of type `T`, if it is one.
+    pub fn as_foreign<T: ForeignObject>(&self) -> Option<&T> {
+        return unsafe { self.value.as_foreign::<T>() };
+    }
+}
+
+impl Clone for Handle {
+    fn clone(&self) -> Self {
+        return Handle::new(self.value, &self.roots);
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.roots.remove(self.value);
+    }
+}
+
+impl fmt::Display for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.value.show())
+    }
+}
+
+impl fmt::Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Handle({})", self)
+    }
+}
@@ -0,0 +1,38 @@
+//! A bytecode virtual machine for the Lox language.
+//!
+//! Embedders create a [`VM`] and hand it source text with
+//! [`VM::interpret`]. Compile and runtime errors come back in the
+//! [`InterpretResult`] rather than being printed, and can be shown to users
+//! with a [`Renderer`]. [`VM::compile`] only compiles, returning a function
+//! that runs the script when called.
+//!
+//! Objects live on the VM's garbage-collected heap. A bare [`Value`] does not
+//! keep its object alive, so reading one is `unsafe`; a [`Handle`] does, and
+//! can be read at any time.
+
+#![allow(clippy::needless_return)]
+
+#[cfg(feature = "serde")]
+pub mod convert;
+pub mod diagnostic;
+pub mod foreign;
+pub mod handle;
+pub mod value;
+pub mod vm;
+
+mod chunk;
+mod compiler;
+mod debug;
+mod memory;
+mod natives;
+mod object;
+mod scanner;
+
+pub use compiler::CompileOptions;
+pub use diagnostic::{Diagnostic, Renderer};
+pub use foreign::{ForeignObject, Tracer};
+pub use handle::Handle;
+pub use object::NativeFn;
+pub use scanner::Span;
+pub use value::Value;
+pub use vm::{InterpretResult, RuntimeError, VMOptions, VM};
@@ -1,42 +1,50 @@
+#![allow(clippy::needless_return)]
+
 use std::env;
 use std::fs::File;
 use std::io::{self, BufRead, Read};
-use vm::InterpretResult;
 
-use crate::vm::VM;
-mod chunk;
-mod debug;
-mod vm;
-mod compiler;
-mod scanner;
+use rustlox::{InterpretResult, Renderer, VMOptions, VM};
 
 fn main() -> Result<(), i32> {
-    println!("Hello, world!");
+    let mut options = VMOptions::default();
+    let mut paths = Vec::new();
+
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--trace" => options.trace_execution = true,
+            "--dump-bytecode" => options.compile.dump_bytecode = true,
+            "--stress-gc" => options.stress_gc = true,
+            _ => paths.push(arg),
+        }
+    }
 
-    let args: Vec<String> = env::args().collect();
+    if (options.trace_execution || options.compile.dump_bytecode) && !cfg!(feature = "trace") {
+        eprintln!("Warning: rustlox was built without the 'trace' feature; ignoring debug options.");
+    }
 
-    match args.len() {
-        1 => return repl(),
-        2 => return run_file(&args[1]),
+    match paths.len() {
+        0 => return repl(options),
+        1 => return run_file(&paths[0], options),
         _ => {
-            println!("Usage: rustlox [path]");
+            println!("Usage: rustlox [--trace] [--dump-bytecode] [--stress-gc] [path]");
             return Err(64);
         }
     }
 }
 
-fn repl() -> Result<(), i32> {
-    let mut vm = VM::new();
+fn repl(options: VMOptions) -> Result<(), i32> {
+    let mut vm = VM::with_options(options);
 
     loop {
         let result = read_line();
         match result {
             Ok(line) => {
-                if line.len() == 0 {
+                if line.is_empty() {
                     println!("bye!");
                     break;
                 } else {
-                    interpret(&mut vm, line);
+                    interpret(&mut vm, "<repl>", &line);
                 }
             }
             Err(err) => {
@@ -49,16 +57,16 @@ fn repl() -> Result<(), i32> {
     return Ok(());
 }
 
-fn run_file(path: &str) -> Result<(), i32> {
-    let mut vm = VM::new();
+fn run_file(path: &str, options: VMOptions) -> Result<(), i32> {
+    let mut vm = VM::with_options(options);
 
     let result = read_file(path);
     match result {
         Ok(line) => {
-            let result = interpret(&mut vm, line);
+            let result = interpret(&mut vm, path, &line);
             match result {
-                InterpretResult::CompileError => return Err(65),
-                InterpretResult::RuntimeError => return Err(70),
+                InterpretResult::CompileError(_) => return Err(65),
+                InterpretResult::RuntimeError(_) => return Err(70),
                 InterpretResult::Ok => return Ok(()),
             };
         }
@@ -69,20 +77,36 @@ fn run_file(path: &str) -> Result<(), i32> {
     };
 }
 
-fn interpret(vm: &mut VM, source: Vec<char>) -> InterpretResult {
-   return vm.interpret(source);
+/// Runs `source`, printing any errors against the file `name`.
+fn interpret(vm: &mut VM, name: &str, source: &str) -> InterpretResult {
+    let result = vm.interpret(source);
+    let renderer = Renderer::new(name, source);
+
+    match &result {
+        InterpretResult::CompileError(diagnostics) => {
+            for diagnostic in diagnostics {
+                eprintln!("{}", renderer.render(diagnostic));
+            }
+        }
+        InterpretResult::RuntimeError(error) => {
+            eprintln!("{}", renderer.render(&error.to_diagnostic()));
+        }
+        InterpretResult::Ok => {}
+    }
+
+    return result;
 }
 
-fn read_line() -> Result<Vec<char>, io::Error> {
+fn read_line() -> Result<String, io::Error> {
     let stdin = io::stdin();
     let mut buffer = String::new();
     stdin.lock().read_line(&mut buffer)?;
-    Ok(buffer.trim().chars().collect())
+    Ok(buffer.trim().to_owned())
 }
 
-fn read_file(file_path: &str) -> Result<Vec<char>, io::Error> {
+fn read_file(file_path: &str) -> Result<String, io::Error> {
     let mut file = File::open(file_path)?;
     let mut buffer = String::new();
     file.read_to_string(&mut buffer)?;
-    Ok(buffer.chars().collect())
+    Ok(buffer)
 }
@@ -0,0 +1,328 @@
+use std::{
+    borrow::Borrow,
+    cell::Cell,
+    collections::HashSet,
+    hash::{Hash, Hasher},
+    mem,
+};
+
+use crate::{
+    chunk::{ColumnRun, LineRun},
+    foreign::Tracer,
+    object::{Obj, ObjKind, ObjRef, ObjString},
+    value::Value,
+};
+
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+
+/// Owns every object allocated by the compiler and the VM, and frees the ones
+/// that are no longer reachable.
+///
+/// The heap does not know where the roots are. The VM marks its roots with
+/// `mark_value`/`mark_object` and then calls `collect`. Collection only ever
+/// happens at VM allocation points, never while compiling, so everything the
+/// compiler allocates stays alive until the compiled script is on the VM
+/// stack. From there its constants are reached by tracing the function.
+pub struct Heap {
+    objects: Vec<ObjRef>,
+    strings: HashSet<InternedString>,
+    gray: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+}
+
+/// Key for the string intern table. Hashes and compares by the string's
+/// contents so the table can be probed with a plain `&str`.
+struct InternedString(ObjRef);
+
+impl InternedString {
+    fn as_str(&self) -> &str {
+        return &self.0.as_string().unwrap().chars;
+    }
+}
+
+impl Borrow<str> for InternedString {
+    fn borrow(&self) -> &str {
+        return self.as_str();
+    }
+}
+
+impl PartialEq for InternedString {
+    fn eq(&self, other: &Self) -> bool {
+        return self.as_str() == other.as_str();
+    }
+}
+
+impl Eq for InternedString {}
+
+impl Hash for InternedString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state);
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        return Heap::new();
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        return Heap {
+            objects: Vec::new(),
+            strings: HashSet::new(),
+            gray: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
+        };
+    }
+
+    /// Returns the interned string with the given contents, allocating it if
+    /// it does not exist yet.
+    pub fn copy_string(&mut self, chars: &str) -> ObjRef {
+        if let Some(interned) = self.strings.get(chars) {
+            return interned.0;
+        }
+
+        return self.allocate_string(chars.to_owned());
+    }
+
+    /// Like `copy_string`, but takes ownership of an already built `String`.
+    pub fn take_string(&mut self, chars: String) -> ObjRef {
+        if let Some(interned) = self.strings.get(chars.as_str()) {
+            return interned.0;
+        }
+
+        return self.allocate_string(chars);
+    }
+
+    /// Returns the interned string with the given contents without
+    /// allocating, or `None` if no such string exists.
+    pub fn find_string(&self, chars: &str) -> Option<ObjRef> {
+        return self.strings.get(chars).map(|interned| interned.0);
+    }
+
+    fn allocate_string(&mut self, chars: String) -> ObjRef {
+        let string = self.allocate(ObjKind::String(ObjString { chars }));
+        self.strings.insert(InternedString(string));
+        return string;
+    }
+
+    pub fn allocate(&mut self, kind: ObjKind) -> ObjRef {
+        let object = ObjRef::new(Box::new(Obj {
+            marked: Cell::new(false),
+            kind,
+        }));
+
+        self.bytes_allocated += size_of(object.get());
+        self.objects.push(object);
+        return object;
+    }
+
+    pub(crate) fn should_collect(&self) -> bool {
+        return self.bytes_allocated > self.next_gc;
+    }
+
+    pub(crate) fn mark_value(&mut self, value: Value) {
+        if let Value::Obj(object) = value {
+            self.mark_object(object);
+        }
+    }
+
+    pub(crate) fn mark_object(&mut self, object: ObjRef) {
+        if object.get().marked.get() {
+            return;
+        }
+
+        object.get().marked.set(true);
+        self.gray.push(object);
+    }
+
+    /// Traces everything reachable from the marked roots and frees the rest.
+    pub(crate) fn collect(&mut self) {
+        self.trace_references();
+
+        // The intern table does not keep strings alive on its own.
+        self.strings.retain(|string| string.0.get().marked.get());
+
+        self.sweep();
+        self.next_gc = self.bytes_allocated.max(GC_INITIAL_THRESHOLD) * GC_HEAP_GROW_FACTOR;
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(object) = self.gray.pop() {
+            self.blacken_object(object);
+        }
+    }
+
+    fn blacken_object(&mut self, object: ObjRef) {
+        match &object.get().kind {
+            ObjKind::String(_) => {}
+            ObjKind::Function(function) => {
+                if let Some(name) = function.name {
+                    self.mark_object(name);
+                }
+
+                for constant in &function.chunk.constants {
+                    self.mark_value(*constant);
+                }
+            }
+            ObjKind::Native(native) => self.mark_object(native.name),
+            ObjKind::Closure(closure) => {
+                self.mark_object(closure.function);
+
+                for upvalue in &closure.upvalues {
+                    self.mark_object(*upvalue);
+                }
+            }
+            ObjKind::Upvalue(upvalue) => {
+                if let Some(value) = upvalue.closed.get() {
+                    self.mark_value(value);
+                }
+            }
+            ObjKind::Class(class) => {
+                self.mark_object(class.name);
+
+                for (name, method) in class.methods.borrow().iter() {
+                    self.mark_object(*name);
+                    self.mark_value(*method);
+                }
+            }
+            ObjKind::Instance(instance) => {
+                self.mark_object(instance.class);
+
+                for (name, value) in instance.fields.borrow().iter() {
+                    self.mark_object(*name);
+                    self.mark_value(*value);
+                }
+            }
+            ObjKind::BoundMethod(bound) => {
+                self.mark_value(bound.receiver);
+                self.mark_object(bound.method);
+            }
+            ObjKind::ForeignClass(class) => self.mark_object(class.name),
+            ObjKind::Foreign(foreign) => foreign.trace(&mut Tracer::new(self)),
+            ObjKind::ForeignMethod(method) => {
+                self.mark_object(method.receiver);
+                self.mark_object(method.name);
+            }
+        }
+    }
+
+    fn sweep(&mut self) {
+        let mut freed = 0;
+
+        self.objects.retain(|object| {
+            if object.get().marked.get() {
+                object.get().marked.set(false);
+                return true;
+            }
+
+            freed += size_of(object.get());
+            unsafe { drop(Box::from_raw(object.as_ptr())) };
+            return false;
+        });
+
+        self.bytes_allocated -= freed;
+    }
+}
+
+/// Approximate number of bytes owned by an object. Only counts parts that do
+/// not change after allocation, so the same amount is released when freeing.
+fn size_of(object: &Obj) -> usize {
+    let owned = match &object.kind {
+        ObjKind::String(string) => string.chars.capacity(),
+        ObjKind::Function(function) => {
+            function.chunk.code.capacity()
+                + function.chunk.constants.capacity() * mem::size_of::<Value>()
+                + function.chunk.lines.capacity() * mem::size_of::<LineRun>()
+                + function.chunk.columns.capacity() * mem::size_of::<ColumnRun>()
+        }
+        ObjKind::Closure(closure) => closure.upvalues.capacity() * mem::size_of::<ObjRef>(),
+        ObjKind::Foreign(foreign) => mem::size_of_val(foreign.as_ref()),
+        ObjKind::Native(_)
+        | ObjKind::Upvalue(_)
+        | ObjKind::Class(_)
+        | ObjKind::Instance(_)
+        | ObjKind::BoundMethod(_)
+        | ObjKind::ForeignClass(_)
+        | ObjKind::ForeignMethod(_) => 0,
+    };
+
+    return mem::size_of::<Obj>() + owned;
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        self.strings.clear();
+
+        for object in self.objects.drain(..) {
+            unsafe { drop(Box::from_raw(object.as_ptr())) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap};
+
+    use super::*;
+    use crate::object::{ObjClass, ObjInstance};
+
+    #[test]
+    fn frees_unmarked_objects_and_their_interned_strings() {
+        let mut heap = Heap::new();
+        let kept = heap.copy_string("kept");
+        heap.copy_string("dropped");
+        let before = heap.bytes_allocated;
+
+        heap.mark_object(kept);
+        heap.collect();
+
+        assert_eq!(heap.objects.len(), 1);
+        assert_eq!(heap.find_string("kept"), Some(kept));
+        assert_eq!(heap.find_string("dropped"), None);
+        assert!(heap.bytes_allocated < before);
+        // Marks are cleared for the next collection.
+        assert!(!kept.get().marked.get());
+    }
+
+    #[test]
+    fn keeps_everything_reachable_from_a_root() {
+        let mut heap = Heap::new();
+        let name = heap.copy_string("Point");
+        let class = heap.allocate(ObjKind::Class(ObjClass {
+            name,
+            methods: RefCell::new(HashMap::new()),
+        }));
+        let field = heap.copy_string("x");
+        let value = heap.copy_string("one");
+        let instance = heap.allocate(ObjKind::Instance(ObjInstance {
+            class,
+            fields: RefCell::new(HashMap::from([(field, Value::Obj(value))])),
+        }));
+
+        heap.mark_object(instance);
+        heap.collect();
+        assert_eq!(heap.objects.len(), 5);
+
+        heap.collect();
+        assert!(heap.objects.is_empty());
+        assert_eq!(heap.bytes_allocated, 0);
+        assert_eq!(heap.find_string("Point"), None);
+    }
+
+    #[test]
+    fn collects_again_once_the_heap_has_grown() {
+        let mut heap = Heap::new();
+        while !heap.should_collect() {
+            heap.copy_string(&heap.objects.len().to_string());
+        }
+
+        heap.collect();
+        assert!(!heap.should_collect());
+        assert_eq!(heap.next_gc, GC_INITIAL_THRESHOLD * GC_HEAP_GROW_FACTOR);
+    }
+}
@@ -0,0 +1,62 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    value::Value,
+    vm::{RuntimeError, VM},
+};
+
+pub fn define_natives(vm: &mut VM) {
+    vm.define_native("clock", 0, clock);
+    vm.define_native("input", 0, input);
+    vm.define_native("str", 1, str);
+    vm.define_native("num", 1, num);
+}
+
+/// Seconds since the Unix epoch.
+fn clock(_vm: &mut VM, _args: &[Value]) -> Result<Value, RuntimeError> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    return Ok(Value::Number(now.as_secs_f64()));
+}
+
+/// Reads a line from stdin without its line ending, or nil at end of input.
+fn input(vm: &mut VM, _args: &[Value]) -> Result<Value, RuntimeError> {
+    let mut line = String::new();
+    let read = vm.read_line(&mut line);
+
+    match read {
+        Ok(0) => return Ok(Value::Nil),
+        Ok(_) => {
+            let trimmed = line.trim_end_matches(['\r', '\n']).len();
+            line.truncate(trimmed);
+            return Ok(vm.new_string(&line).value());
+        }
+        Err(err) => return Err(RuntimeError::new(format!("Could not read input: {}.", err))),
+    }
+}
+
+fn str(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args[0].is_string() {
+        return Ok(args[0]);
+    }
+
+    return Ok(vm.new_string(&args[0].show().to_string()).value());
+}
+
+fn num(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if let Value::Number(_) = args[0] {
+        return Ok(args[0]);
+    }
+
+    let string = match args[0].as_string() {
+        Some(string) => string,
+        None => return Err(RuntimeError::new("Argument to 'num' must be a string or number.")),
+    };
+
+    match string.chars.trim().parse::<f64>() {
+        Ok(value) => return Ok(Value::Number(value)),
+        Err(_) => {
+            let message = format!("Can't convert '{}' to a number.", string.chars);
+            return Err(RuntimeError::new(message));
+        }
+    }
+}
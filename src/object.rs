@@ -0,0 +1,246 @@
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    ptr::NonNull,
+};
+
+use crate::{
+    chunk::Chunk,
+    foreign::{ForeignConstructor, ForeignObject},
+    value::Value,
+    vm::{RuntimeError, VM},
+};
+
+pub struct Obj {
+    pub marked: Cell<bool>,
+    pub kind: ObjKind,
+}
+
+pub enum ObjKind {
+    String(ObjString),
+    Function(ObjFunction),
+    Native(ObjNative),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+    ForeignClass(ObjForeignClass),
+    Foreign(Box<dyn ForeignObject>),
+    ForeignMethod(ObjForeignMethod),
+}
+
+pub struct ObjString {
+    pub chars: String,
+}
+
+pub struct ObjFunction {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    // None for the top-level script.
+    pub name: Option<ObjRef>,
+    // Numbers the `VM::interpret` call that compiled the function, so that
+    // errors can tell whether it came from the source being reported on.
+    pub script: u32,
+}
+
+impl ObjFunction {
+    pub fn new(name: Option<ObjRef>, script: u32) -> ObjFunction {
+        return ObjFunction {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            name,
+            script,
+        };
+    }
+}
+
+/// A native function. An object it returns must be one of its arguments, or
+/// come from a `Handle`, e.g. `vm.new_string("...").value()`.
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, RuntimeError>;
+
+/// A function implemented in Rust and callable from scripts.
+pub struct ObjNative {
+    pub name: ObjRef,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+/// A variable captured by a closure. While the variable is still live on the
+/// VM stack the upvalue is open and refers to its slot; once the variable goes
+/// out of scope its value is moved into `closed`.
+pub struct ObjUpvalue {
+    pub location: usize,
+    pub closed: Cell<Option<Value>>,
+}
+
+pub struct ObjClass {
+    pub name: ObjRef,
+    pub methods: RefCell<HashMap<ObjRef, Value>>,
+}
+
+pub struct ObjInstance {
+    pub class: ObjRef,
+    pub fields: RefCell<HashMap<ObjRef, Value>>,
+}
+
+/// A method closure together with the instance it was accessed on.
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+/// A class implemented in Rust. Calling it runs `constructor` to create the
+/// foreign object.
+pub struct ObjForeignClass {
+    pub name: ObjRef,
+    pub arity: usize,
+    pub constructor: ForeignConstructor,
+}
+
+/// A method looked up on a foreign object without calling it, as in
+/// `var push = list.push;`. Calling it invokes `name` on `receiver`.
+pub struct ObjForeignMethod {
+    pub receiver: ObjRef,
+    pub name: ObjRef,
+}
+
+/// A pointer to an object on the VM's heap. Two pointers are equal only when
+/// they refer to the same allocation.
+///
+/// Outside the crate this is opaque: the object may already have been freed,
+/// so its contents are only reachable through the `unsafe` accessors on
+/// `Value`, or safely through a `Handle`.
+#[derive(Clone, Copy)]
+pub struct ObjRef(NonNull<Obj>);
+
+impl ObjRef {
+    pub(crate) fn new(obj: Box<Obj>) -> ObjRef {
+        return ObjRef(NonNull::from(Box::leak(obj)));
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut Obj {
+        return self.0.as_ptr();
+    }
+
+    /// The object itself. Within the crate every reachable `ObjRef` is alive:
+    /// the heap only frees objects once nothing in the VM refers to them.
+    pub(crate) fn get(&self) -> &Obj {
+        return unsafe { self.0.as_ref() };
+    }
+
+    pub(crate) fn as_string(&self) -> Option<&ObjString> {
+        return match &self.get().kind {
+            ObjKind::String(string) => Some(string),
+            _ => None,
+        };
+    }
+
+    pub(crate) fn as_function(&self) -> Option<&ObjFunction> {
+        return match &self.get().kind {
+            ObjKind::Function(function) => Some(function),
+            _ => None,
+        };
+    }
+
+    pub(crate) fn as_closure(&self) -> Option<&ObjClosure> {
+        return match &self.get().kind {
+            ObjKind::Closure(closure) => Some(closure),
+            _ => None,
+        };
+    }
+
+    pub(crate) fn as_upvalue(&self) -> Option<&ObjUpvalue> {
+        return match &self.get().kind {
+            ObjKind::Upvalue(upvalue) => Some(upvalue),
+            _ => None,
+        };
+    }
+
+    pub(crate) fn as_class(&self) -> Option<&ObjClass> {
+        return match &self.get().kind {
+            ObjKind::Class(class) => Some(class),
+            _ => None,
+        };
+    }
+
+    pub(crate) fn as_instance(&self) -> Option<&ObjInstance> {
+        return match &self.get().kind {
+            ObjKind::Instance(instance) => Some(instance),
+            _ => None,
+        };
+    }
+
+    pub(crate) fn as_foreign(&self) -> Option<&dyn ForeignObject> {
+        return match &self.get().kind {
+            ObjKind::Foreign(object) => Some(object.as_ref()),
+            _ => None,
+        };
+    }
+
+    /// Returns the foreign object if it is a `T`.
+    pub(crate) fn downcast_foreign<T: ForeignObject>(&self) -> Option<&T> {
+        let object: &dyn Any = self.as_foreign()?;
+        return object.downcast_ref::<T>();
+    }
+}
+
+impl PartialEq for ObjRef {
+    fn eq(&self, other: &Self) -> bool {
+        return self.0 == other.0;
+    }
+}
+
+impl Eq for ObjRef {}
+
+impl Hash for ObjRef {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl fmt::Debug for ObjRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Only the address: the object may no longer exist.
+        write!(f, "ObjRef({:p})", self.0)
+    }
+}
+
+impl fmt::Display for Obj {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ObjKind::String(string) => write!(f, "{}", string.chars),
+            ObjKind::Function(function) => write!(f, "{}", function),
+            ObjKind::Native(_) => write!(f, "<native fn>"),
+            ObjKind::Closure(closure) => write!(f, "{}", closure.function.get()),
+            ObjKind::Upvalue(_) => write!(f, "upvalue"),
+            ObjKind::Class(class) => write!(f, "{}", class.name.get()),
+            ObjKind::Instance(instance) => {
+                write!(f, "{} instance", instance.class.as_class().unwrap().name.get())
+            }
+            ObjKind::BoundMethod(bound) => write!(f, "{}", bound.method.get()),
+            ObjKind::ForeignClass(class) => write!(f, "{}", class.name.get()),
+            ObjKind::Foreign(object) => write!(f, "{}", ForeignObject::to_string(object.as_ref())),
+            ObjKind::ForeignMethod(method) => write!(f, "<fn {}>", method.name.get()),
+        }
+    }
+}
+
+impl fmt::Display for ObjFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name {
+            Some(name) => write!(f, "<fn {}>", name.get()),
+            None => write!(f, "<script>"),
+        }
+    }
+}
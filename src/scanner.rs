@@ -1,37 +1,49 @@
 use num_derive::FromPrimitive;
 
 pub struct Scanner<'a> {
-    source: &'a Vec<char>,
+    source: &'a str,
+    // Byte offsets of the token being scanned and the next character.
     start: usize,
     current: usize,
     line: i32,
+    // Column of the next character, counted in chars from 1.
+    column: i32,
+    // Position of the token currently being scanned.
+    start_line: i32,
+    start_column: i32,
 }
 
-pub struct Token {
+/// A byte range in the source text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Token<'a> {
     pub token_type: TokenType,
-    pub lexeme: String,
+    /// The token's text, or the message for `TokenType::Error` tokens.
+    pub lexeme: &'a str,
     pub line: i32,
+    pub column: i32,
+    pub span: Span,
 }
 
-impl Token {
-    pub fn empty() -> Token {
+impl Token<'_> {
+    pub fn empty() -> Token<'static> {
         return Token {
             token_type: TokenType::Error,
-            lexeme: "Empty token.".to_owned(),
+            lexeme: "Empty token.",
             line: -1,
+            column: -1,
+            span: Span::default(),
         };
     }
-
-    pub fn clone(&self) -> Self {
-        Token {
-            token_type: self.token_type.clone(),
-            lexeme: self.lexeme.clone(),
-            line: self.line,
-        }
-    }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, FromPrimitive, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, PartialEq, Eq, Hash, FromPrimitive, Clone, Copy)]
 pub enum TokenType {
     // Single-character tokens.
     LeftParen,
@@ -80,19 +92,24 @@ pub enum TokenType {
 }
 
 impl<'a> Scanner<'a> {
-    pub fn new(source: &Vec<char>) -> Scanner {
+    pub fn new(source: &'a str) -> Scanner<'a> {
         return Scanner {
-            source: source,
+            source,
             start: 0,
             current: 0,
             line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
         };
     }
 
-    pub fn scan_token(&mut self) -> Token {
+    pub fn scan_token(&mut self) -> Token<'a> {
         self.skip_whitespace();
 
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
 
         if self.is_at_end() {
             return self.make_token(TokenType::EOF);
@@ -143,7 +160,7 @@ impl<'a> Scanner<'a> {
             '"' => self.make_string(),
             a if self.is_alpha(a) => self.make_identifier(),
             d if self.is_digit(d) => self.make_number(),
-            _ => self.error_token(&format!("Unexpected character: {}.", c)),
+            _ => self.error_token("Unexpected character."),
         };
     }
 
@@ -151,37 +168,46 @@ impl<'a> Scanner<'a> {
         return self.current >= self.source.len();
     }
 
-    fn make_token(&self, token_type: TokenType) -> Token {
+    fn make_token(&self, token_type: TokenType) -> Token<'a> {
         return Token {
-            token_type: token_type,
-            lexeme: self.source[self.start..self.current].iter().collect(),
-            line: self.line,
+            token_type,
+            lexeme: &self.source[self.start..self.current],
+            line: self.start_line,
+            column: self.start_column,
+            span: self.span(),
         };
     }
 
-    fn error_token(&self, message: &str) -> Token {
+    fn error_token(&self, message: &'static str) -> Token<'a> {
         return Token {
             token_type: TokenType::Error,
-            lexeme: message.to_string(),
-            line: self.line,
+            lexeme: message,
+            line: self.start_line,
+            column: self.start_column,
+            span: self.span(),
+        };
+    }
+
+    fn span(&self) -> Span {
+        return Span {
+            start: self.start,
+            end: self.current,
         };
     }
 
     fn advance(&mut self) -> char {
-        self.current += 1;
-        return self.source[self.current - 1];
+        let c = self.peek();
+        self.current += c.len_utf8();
+        self.column += 1;
+        return c;
     }
 
     fn match_char(&mut self, expected: char) -> bool {
-        if self.is_at_end() {
-            return false;
-        }
-
-        if self.source[self.current] != expected {
+        if self.peek() != expected {
             return false;
         }
 
-        self.current += 1;
+        self.advance();
         return true;
     }
 
@@ -193,8 +219,8 @@ impl<'a> Scanner<'a> {
                     self.advance();
                 }
                 '\n' => {
-                    self.line += 1;
                     self.advance();
+                    self.new_line();
                 }
                 '/' if self.peek_next() == '/' => {
                     while self.peek() != '\n' && !self.is_at_end() {
@@ -206,29 +232,28 @@ impl<'a> Scanner<'a> {
         }
     }
 
-    fn peek(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
+    /// Call after consuming a newline.
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.column = 1;
+    }
 
-        return self.source[self.current];
+    /// The next character, or '\0' at the end of the source.
+    fn peek(&self) -> char {
+        return self.source[self.current..].chars().next().unwrap_or('\0');
     }
 
     fn peek_next(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
-
-        return self.source[self.current + 1];
+        let mut chars = self.source[self.current..].chars();
+        chars.next();
+        return chars.next().unwrap_or('\0');
     }
 
-    fn make_string(&mut self) -> Token {
+    fn make_string(&mut self) -> Token<'a> {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            if self.advance() == '\n' {
+                self.new_line();
             }
-
-            self.advance();
         }
 
         if self.is_at_end() {
@@ -240,10 +265,10 @@ impl<'a> Scanner<'a> {
     }
 
     fn is_digit(&self, c: char) -> bool {
-        return c >= '0' && c <= '9';
+        return c.is_ascii_digit();
     }
 
-    fn make_number(&mut self) -> Token {
+    fn make_number(&mut self) -> Token<'a> {
         while self.is_digit(self.peek()) {
             self.advance();
         }
@@ -261,10 +286,10 @@ impl<'a> Scanner<'a> {
     }
 
     fn is_alpha(&self, c: char) -> bool {
-        return (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_';
+        return c.is_ascii_alphabetic() || c == '_';
     }
 
-    fn make_identifier(&mut self) -> Token {
+    fn make_identifier(&mut self) -> Token<'a> {
         while self.is_alpha(self.peek()) || self.is_digit(self.peek()) {
             self.advance();
         }
@@ -273,38 +298,38 @@ impl<'a> Scanner<'a> {
     }
 
     fn identifier_type(&self) -> TokenType {
-        let c = self.source[self.current];
-
-        return match c {
-            'a' => self.check_keyword("and", TokenType::And),
-            'c' => self.check_keyword("class", TokenType::Class),
-            'e' => self.check_keyword("else", TokenType::Else),
-            'f' if self.current - self.start > 1 => match self.source[self.start + 1] {
-                'a' => self.check_keyword("false", TokenType::False),
-                'o' => self.check_keyword("for", TokenType::For),
-                'u' => self.check_keyword("fun", TokenType::Fun),
+        // Identifiers are ASCII, so indexing bytes is safe here.
+        let bytes = self.source.as_bytes();
+
+        return match bytes[self.start] {
+            b'a' => self.check_keyword("and", TokenType::And),
+            b'c' => self.check_keyword("class", TokenType::Class),
+            b'e' => self.check_keyword("else", TokenType::Else),
+            b'f' if self.current - self.start > 1 => match bytes[self.start + 1] {
+                b'a' => self.check_keyword("false", TokenType::False),
+                b'o' => self.check_keyword("for", TokenType::For),
+                b'u' => self.check_keyword("fun", TokenType::Fun),
                 _ => TokenType::Identifier,
             },
-            'i' => self.check_keyword("if", TokenType::If),
-            'n' => self.check_keyword("nil", TokenType::Nil),
-            'o' => self.check_keyword("or", TokenType::Or),
-            'p' => self.check_keyword("print", TokenType::Print),
-            'r' => self.check_keyword("return", TokenType::Return),
-            's' => self.check_keyword("super", TokenType::Super),
-            't' if self.current - self.start > 1 => match self.source[self.start + 1] {
-                'h' => self.check_keyword("this", TokenType::This),
-                'r' => self.check_keyword("true", TokenType::True),
+            b'i' => self.check_keyword("if", TokenType::If),
+            b'n' => self.check_keyword("nil", TokenType::Nil),
+            b'o' => self.check_keyword("or", TokenType::Or),
+            b'p' => self.check_keyword("print", TokenType::Print),
+            b'r' => self.check_keyword("return", TokenType::Return),
+            b's' => self.check_keyword("super", TokenType::Super),
+            b't' if self.current - self.start > 1 => match bytes[self.start + 1] {
+                b'h' => self.check_keyword("this", TokenType::This),
+                b'r' => self.check_keyword("true", TokenType::True),
                 _ => TokenType::Identifier,
             },
-            'v' => self.check_keyword("var", TokenType::Var),
-            'w' => self.check_keyword("while", TokenType::While),
+            b'v' => self.check_keyword("var", TokenType::Var),
+            b'w' => self.check_keyword("while", TokenType::While),
             _ => TokenType::Identifier,
         };
     }
 
     fn check_keyword(&self, expected: &str, token_type: TokenType) -> TokenType {
-        let actual: String = self.source[self.start..=self.current].iter().collect();
-        if actual == expected {
+        if &self.source[self.start..self.current] == expected {
             return token_type;
         }
 
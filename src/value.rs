@@ -0,0 +1,92 @@
+use std::fmt;
+
+use crate::{
+    foreign::ForeignObject,
+    object::{ObjRef, ObjString},
+};
+
+/// A Lox value.
+///
+/// `Value::Obj` points into the VM's heap and is only alive while the VM can
+/// reach it: on the stack (such as the arguments of a running native), in a
+/// global, in a field, or through a `Handle`. Once nothing refers to it, the
+/// next collection frees it, so a `Value` kept elsewhere can dangle. Reading
+/// an object through a bare `Value` is therefore `unsafe`; hold a `Handle`
+/// to keep a value alive and read it safely. The VM checks objects handed
+/// back to it, as arguments to `VM::call` or results of natives: one that is
+/// neither on its stack nor held by a handle since the last collection is
+/// rejected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(ObjRef),
+}
+
+impl Value {
+    pub fn is_falsey(&self) -> bool {
+        return matches!(self, Value::Nil | Value::Bool(false));
+    }
+
+    /// The contents of the value if it is a string.
+    ///
+    /// # Safety
+    ///
+    /// The value must still be alive, see [`Value`].
+    pub unsafe fn as_str(&self) -> Option<&str> {
+        return self.as_string().map(|string| string.chars.as_str());
+    }
+
+    /// The value as a foreign object of type `T`, if it is one.
+    ///
+    /// # Safety
+    ///
+    /// The value must still be alive, see [`Value`].
+    pub unsafe fn as_foreign<T: ForeignObject>(&self) -> Option<&T> {
+        return match self {
+            Value::Obj(obj) => obj.downcast_foreign::<T>(),
+            _ => None,
+        };
+    }
+
+    /// Formats the value the way `print` does.
+    ///
+    /// # Safety
+    ///
+    /// The value must still be alive while the result is formatted, see
+    /// [`Value`].
+    pub unsafe fn display(&self) -> impl fmt::Display {
+        return self.show();
+    }
+
+    pub(crate) fn is_string(&self) -> bool {
+        return self.as_string().is_some();
+    }
+
+    pub(crate) fn as_string(&self) -> Option<&ObjString> {
+        return match self {
+            Value::Obj(obj) => obj.as_string(),
+            _ => None,
+        };
+    }
+
+    /// Formats the value the way `print` does. Values inside the VM are
+    /// always alive, so this is safe within the crate.
+    pub(crate) fn show(&self) -> Show {
+        return Show(*self);
+    }
+}
+
+pub(crate) struct Show(Value);
+
+impl fmt::Display for Show {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Number(value) => write!(f, "{}", value),
+            Value::Obj(obj) => write!(f, "{}", obj.get()),
+        }
+    }
+}
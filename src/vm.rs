@@ -1,103 +1,1639 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt,
+    io::{self, BufRead, Write},
+    mem,
+    rc::Rc,
+};
+
 use crate::{
-    chunk::{Chunk, OpCode, Value},
-    compiler::{compile}, debug::{self},
+    chunk::{Chunk, OpCode},
+    compiler::{compile, CompileOptions}, debug::{self},
+    diagnostic::{Diagnostic, Severity},
+    foreign::{ForeignConstructor, ForeignObject},
+    handle::{Handle, Roots},
+    memory::Heap,
+    natives,
+    object::{
+        NativeFn, ObjBoundMethod, ObjClass, ObjClosure, ObjForeignClass, ObjForeignMethod,
+        ObjInstance, ObjKind, ObjNative, ObjRef, ObjUpvalue,
+    },
+    value::Value,
 };
 use num_traits::FromPrimitive;
 
+const FRAMES_MAX: usize = 64;
+
 pub struct VM {
-    // chunk: Option<Chunk>,
-    ip: usize,
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
+    globals: HashMap<ObjRef, Value>,
+    // Upvalues still pointing into the stack, sorted by stack slot.
+    open_upvalues: Vec<ObjRef>,
+    init_string: ObjRef,
+    // Objects kept alive by handles the host holds.
+    roots: Rc<Roots>,
+    heap: Heap,
+    options: VMOptions,
+    // Where `print` statements write.
+    output: Box<dyn Write>,
+    // Where execution traces and bytecode dumps are written.
+    debug_output: Box<dyn Write>,
+    // Where the `input` native reads from. None reads from stdin, which is
+    // locked per read so the host can keep using it too.
+    input: Option<Box<dyn BufRead>>,
+    // How many scripts `interpret` has compiled.
+    scripts: u32,
+}
+
+/// Debugging aids for running scripts.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VMOptions {
+    /// Print the stack and each instruction before executing it. Like
+    /// `CompileOptions`, only takes effect when built with the `trace`
+    /// feature.
+    pub trace_execution: bool,
+    /// Collect garbage at every allocation instead of when the heap has
+    /// grown, so that values the VM forgets to keep alive are freed at once.
+    /// Very slow; meant for testing the collector.
+    pub stress_gc: bool,
+    pub compile: CompileOptions,
+}
+
+/// An ongoing function call: the function being run, where it is in that
+/// function's code, and where its locals start on the VM stack.
+struct CallFrame {
+    closure: ObjRef,
+    ip: usize,
+    slot_base: usize,
+}
+
+impl CallFrame {
+    fn closure(&self) -> &ObjClosure {
+        return self.closure.as_closure().unwrap();
+    }
+
+    fn chunk(&self) -> &Chunk {
+        return &self.closure().function.as_function().unwrap().chunk;
+    }
+}
+
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub message: String,
+    /// The call stack at the point of the error, innermost call first. Filled
+    /// in by the VM as the error unwinds out of `VM::call`.
+    pub trace: Vec<TraceFrame>,
+}
+
+#[derive(Debug)]
+pub struct TraceFrame {
+    /// None for the top-level script.
+    pub function: Option<String>,
+    pub line: i32,
+    pub column: i32,
+    /// Whether the frame is running code compiled from the source passed to
+    /// the `VM::interpret` call that reported the error. False for functions
+    /// defined by earlier calls, such as previous lines in a REPL.
+    pub in_source: bool,
+    script: u32,
+}
+
+impl RuntimeError {
+    pub fn new(message: impl Into<String>) -> RuntimeError {
+        return RuntimeError {
+            message: message.into(),
+            trace: Vec::new(),
+        };
+    }
+}
+
+impl RuntimeError {
+    /// Positions the error at the innermost call and lists the rest of the
+    /// stack trace as notes. The position is left out if that call is not
+    /// running code from the source that was interpreted, since it would
+    /// point into some other text.
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let (line, column) = match self.trace.first() {
+            Some(frame) if frame.in_source => (frame.line, frame.column),
+            _ => (0, 0),
+        };
+
+        let notes = self
+            .trace
+            .iter()
+            .map(|frame| match &frame.function {
+                Some(name) => format!("[line {}:{}] in {}()", frame.line, frame.column, name),
+                None => format!("[line {}:{}] in script", frame.line, frame.column),
+            })
+            .collect();
+
+        return Diagnostic {
+            severity: Severity::Error,
+            message: self.message.clone(),
+            line,
+            column,
+            span: Default::default(),
+            near: None,
+            labels: Vec::new(),
+            notes,
+        };
+    }
 }
 
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.message)?;
+
+        for frame in &self.trace {
+            match &frame.function {
+                Some(name) => writeln!(f, "[line {}:{}] in {}()", frame.line, frame.column, name)?,
+                None => writeln!(f, "[line {}:{}] in script", frame.line, frame.column)?,
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+/// The outcome of `VM::interpret`. Errors are returned rather than printed
+/// so that callers can render or inspect them; see `diagnostic::Renderer`.
+#[derive(Debug)]
 pub enum InterpretResult {
     Ok,
-    CompileError,
-    RuntimeError,
+    CompileError(Vec<Diagnostic>),
+    RuntimeError(RuntimeError),
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        return VM::new();
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
-        VM {
-            ip: 0,
+        return VM::with_options(VMOptions::default());
+    }
+
+    pub fn with_options(options: VMOptions) -> VM {
+        let mut heap = Heap::new();
+        let init_string = heap.copy_string("init");
+
+        let mut vm = VM {
+            frames: Vec::with_capacity(FRAMES_MAX),
             stack: Vec::new(),
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            init_string,
+            roots: Rc::default(),
+            heap,
+            options,
+            output: Box::new(io::stdout()),
+            debug_output: Box::new(io::stdout()),
+            input: None,
+            scripts: 0,
+        };
+
+        natives::define_natives(&mut vm);
+        return vm;
+    }
+
+    /// Sends the output of `print` statements to `output` instead of stdout.
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+
+    /// Sends execution traces and bytecode dumps to `output` instead of
+    /// stdout.
+    pub fn set_debug_output(&mut self, output: impl Write + 'static) {
+        self.debug_output = Box::new(output);
+    }
+
+    /// Makes the `input` native read lines from `input` instead of stdin.
+    pub fn set_input(&mut self, input: impl BufRead + 'static) {
+        self.input = Some(Box::new(input));
+    }
+
+    /// Reads a line from the VM's input into `buffer`, returning the number of
+    /// bytes read.
+    pub(crate) fn read_line(&mut self, buffer: &mut String) -> io::Result<usize> {
+        return match &mut self.input {
+            Some(input) => input.read_line(buffer),
+            None => io::stdin().lock().read_line(buffer),
+        };
+    }
+
+    /// Exposes a Rust function to scripts as a global named `name`.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        // Keep both objects on the stack so a collection triggered by the
+        // second allocation cannot free the first.
+        let name = self.take_string(name.to_owned());
+        self.push(Value::Obj(name));
+        let native = self.allocate(ObjKind::Native(ObjNative {
+            name,
+            arity,
+            function,
+        }));
+        self.push(Value::Obj(native));
+
+        self.globals.insert(name, Value::Obj(native));
+
+        self.stack.truncate(self.stack.len() - 2);
+    }
+
+    /// Exposes a Rust type to scripts as a class named `name`. Calling the
+    /// class with `arity` arguments runs `constructor` to create the object.
+    pub fn define_foreign_class(&mut self, name: &str, arity: usize, constructor: ForeignConstructor) {
+        let name = self.take_string(name.to_owned());
+        self.push(Value::Obj(name));
+        let class = self.allocate(ObjKind::ForeignClass(ObjForeignClass {
+            name,
+            arity,
+            constructor,
+        }));
+        self.push(Value::Obj(class));
+
+        self.globals.insert(name, Value::Obj(class));
+
+        self.stack.truncate(self.stack.len() - 2);
+    }
+
+    /// Moves `object` onto the heap so it can be handed to scripts.
+    ///
+    /// This may collect garbage before `object` is on the heap, so any values
+    /// it holds must already be reachable some other way, such as arguments
+    /// of the running native or values held by a `Handle`.
+    pub fn new_foreign(&mut self, object: impl ForeignObject) -> Handle {
+        if self.should_collect() {
+            self.collect_garbage();
         }
+
+        let object = self.heap.allocate(ObjKind::Foreign(Box::new(object)));
+        return Handle::new(Value::Obj(object), &self.roots);
     }
 
-    pub fn interpret(&mut self, source: Vec<char>) -> InterpretResult {
-        let result = compile(&source);
+    /// Creates a Lox string.
+    pub fn new_string(&mut self, chars: &str) -> Handle {
+        let string = self.take_string(chars.to_owned());
+        return Handle::new(Value::Obj(string), &self.roots);
+    }
+
+    /// Compiles `source` without running it. The result is a function that
+    /// takes no arguments and runs the script when passed to `VM::call`, as
+    /// many times as needed.
+    pub fn compile(&mut self, source: &str) -> Result<Handle, Vec<Diagnostic>> {
+        self.scripts += 1;
+
+        let function = compile(
+            source,
+            &mut self.heap,
+            self.options.compile,
+            self.scripts,
+            &mut *self.debug_output,
+        )?;
+
+        self.push(Value::Obj(function));
+        let closure = self.allocate(ObjKind::Closure(ObjClosure {
+            function,
+            upvalues: Vec::new(),
+        }));
+        self.stack.pop();
 
-        return match result {
-            Some(chunk) => self.run(&chunk),
-            None => return InterpretResult::CompileError,
+        return Ok(Handle::new(Value::Obj(closure), &self.roots));
+    }
+
+    /// Compiles and runs `source`. Globals it defines stay around for later
+    /// calls, so a REPL can feed the VM one line at a time.
+    pub fn interpret(&mut self, source: &str) -> InterpretResult {
+        let script = match self.compile(source) {
+            Ok(script) => script,
+            Err(diagnostics) => return InterpretResult::CompileError(diagnostics),
+        };
+        let number = self.scripts;
+
+        return match self.call(&script, &[]) {
+            Ok(_) => InterpretResult::Ok,
+            Err(mut error) => {
+                for frame in &mut error.trace {
+                    frame.in_source = frame.script == number;
+                }
+
+                InterpretResult::RuntimeError(error)
+            }
         };
     }
 
-    fn run(&mut self, chunk: &Chunk) -> InterpretResult {
-        self.ip = 0;
-        
-        loop {
-            print!("          ");
+    /// Returns the value of the global variable `name`, if it is defined.
+    /// The handle keeps the value alive even after the script reassigns the
+    /// global, e.g. to hold on to a callback it registered.
+    pub fn get_global(&self, name: &str) -> Option<Handle> {
+        let name = self.heap.find_string(name)?;
+        return self.globals.get(&name).map(|value| Handle::new(*value, &self.roots));
+    }
+
+    /// Defines or overwrites the global variable `name`.
+    ///
+    /// # Panics
+    ///
+    /// If `value` is a handle from another VM.
+    pub fn set_global(&mut self, name: &str, value: &Handle) {
+        self.check_handle(value);
+        let value = value.value();
 
-            for slot in &self.stack {
-                print!("[ ");
-                debug::print_value(*slot);
-                print!(" ]");
+        // Keep the value rooted while the name is allocated.
+        self.push(value);
+        let name = self.take_string(name.to_owned());
+        self.globals.insert(name, value);
+        self.stack.pop();
+    }
+
+    /// Calls a closure, bound method, class or native function with `args`
+    /// and runs it to completion, returning a handle to its result. Natives
+    /// may use this to call back into Lox while the VM is already running.
+    ///
+    /// On error the VM unwinds back to where it was before the call, so the
+    /// caller can carry on.
+    ///
+    /// # Panics
+    ///
+    /// If `callable` is a handle from another VM, or an argument is an object
+    /// that `VM::handle` would reject.
+    pub fn call(&mut self, callable: &Handle, args: &[Value]) -> Result<Handle, RuntimeError> {
+        self.check_handle(callable);
+        self.check_args(args);
+        let callable = callable.value();
+
+        let stack_base = self.stack.len();
+        self.push(callable);
+        for arg in args {
+            self.push(*arg);
+        }
+
+        return self.run_call(stack_base, |vm| vm.call_value(callable, args.len()));
+    }
+
+    /// Calls the method `name` on `receiver`, an instance or foreign object,
+    /// the way `receiver.name(args)` would in a script.
+    ///
+    /// # Panics
+    ///
+    /// As for `VM::call`.
+    pub fn invoke(&mut self, receiver: &Handle, name: &str, args: &[Value]) -> Result<Handle, RuntimeError> {
+        self.check_handle(receiver);
+        self.check_args(args);
+
+        let stack_base = self.stack.len();
+        self.push(receiver.value());
+        for arg in args {
+            self.push(*arg);
+        }
+
+        // The name sits below the call so it stays rooted until the end.
+        let name = self.take_string(name.to_owned());
+        self.stack.insert(stack_base, Value::Obj(name));
+
+        return self.run_call(stack_base, |vm| vm.invoke_method(name, args.len()));
+    }
+
+    /// Starts a call whose callee and arguments are on the stack above
+    /// `stack_base` and runs it to completion. Afterwards the stack is back
+    /// at `stack_base`, even if the call failed.
+    fn run_call<F>(&mut self, stack_base: usize, start: F) -> Result<Handle, RuntimeError>
+    where
+        F: FnOnce(&mut VM) -> Result<(), RuntimeError>,
+    {
+        let base = self.frames.len();
+        let result = start(self).and_then(|_| {
+            // Natives have already finished; only Lox code needs running.
+            if self.frames.len() > base {
+                return self.execute(base);
             }
 
-            println!();
+            return Ok(());
+        });
+
+        if let Err(mut error) = result {
+            error.trace.extend(self.stack_trace(base));
+            self.close_upvalues(stack_base);
+            self.frames.truncate(base);
+            self.stack.truncate(stack_base);
+            return Err(error);
+        }
 
-            debug::disassemble_instruction(chunk, self.ip);
+        let result = self.pop()?;
+        self.stack.truncate(stack_base);
+        return Ok(Handle::new(result, &self.roots));
+    }
+
+    /// Keeps `value` alive until the returned handle and its clones are
+    /// dropped.
+    ///
+    /// # Panics
+    ///
+    /// If `value` is an object the VM cannot tell is alive. Only objects on
+    /// its stack, such as the arguments of the running native, and those
+    /// held by a handle since the last garbage collection are accepted.
+    pub fn handle(&self, value: Value) -> Handle {
+        assert!(self.is_alive(value), "Value is not alive in this VM.");
+        return Handle::new(value, &self.roots);
+    }
 
-            let instruction = OpCode::from_u8(self.read_byte(chunk));
+    /// Like `handle`, but also accepts objects the VM cannot vouch for, such
+    /// as values a foreign object holds and traces.
+    ///
+    /// # Safety
+    ///
+    /// `value` must not have been freed, and must belong to this VM.
+    pub unsafe fn handle_unchecked(&self, value: Value) -> Handle {
+        return Handle::new(value, &self.roots);
+    }
+
+    fn check_handle(&self, handle: &Handle) {
+        assert!(handle.belongs_to(&self.roots), "Handle belongs to another VM.");
+    }
+
+    fn check_args(&self, args: &[Value]) {
+        for arg in args {
+            assert!(self.is_alive(*arg), "Argument is not alive in this VM.");
+        }
+    }
+
+    /// Whether `value` is certainly alive in this VM: anything but an object,
+    /// or an object on the stack or held by a handle since the last
+    /// collection. Values from natives and foreign objects are checked with
+    /// this before scripts see them.
+    fn is_alive(&self, value: Value) -> bool {
+        return match value {
+            Value::Obj(object) => self.roots.contains(object) || self.stack.iter().rev().any(|slot| *slot == value),
+            _ => true,
+        };
+    }
+
+    fn check_returned(&self, value: Value) -> Result<Value, RuntimeError> {
+        if !self.is_alive(value) {
+            return Err(RuntimeError::new("Native code returned an object that is not alive in this VM."));
+        }
+
+        return Ok(value);
+    }
+
+    /// Runs until the call stack shrinks back to `base` frames, leaving the
+    /// value returned by the last frame on the stack.
+    fn execute(&mut self, base: usize) -> Result<(), RuntimeError> {
+        loop {
+            if cfg!(feature = "trace") && self.options.trace_execution {
+                self.trace_instruction().map_err(output_error)?;
+            }
+
+            let instruction_byte = self.read_byte();
+            let instruction = OpCode::from_u8(instruction_byte);
             match instruction {
                 Some(value) => match value {
-                    OpCode::Constant => {
-                        let index = self.read_byte(chunk);
-                        let constant = chunk.read_constant(index);
+                    OpCode::Constant | OpCode::ConstantLong => {
+                        let constant = self.read_constant(value == OpCode::ConstantLong);
                         self.push(constant);
                     }
-                    OpCode::Add => self.binary_op(|a, b| a + b),
-                    OpCode::Subtract => self.binary_op(|a, b| a - b),
-                    OpCode::Multiply => self.binary_op(|a, b| a * b),
-                    OpCode::Divide => self.binary_op(|a, b| a / b),
-                    OpCode::Negate => {
-                        let value = self.pop();
-                        self.push(-value);
+                    OpCode::Nil => self.push(Value::Nil),
+                    OpCode::True => self.push(Value::Bool(true)),
+                    OpCode::False => self.push(Value::Bool(false)),
+                    OpCode::Pop => {
+                        self.pop()?;
+                    }
+                    OpCode::GetLocal => {
+                        let slot = self.frame().slot_base + self.read_byte() as usize;
+                        self.push(self.stack[slot]);
+                    }
+                    OpCode::SetLocal => {
+                        let slot = self.frame().slot_base + self.read_byte() as usize;
+                        self.stack[slot] = self.peek(0)?;
+                    }
+                    OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                        let name = self.read_string(value == OpCode::GetGlobalLong);
+                        match self.globals.get(&name) {
+                            Some(value) => self.push(*value),
+                            None => {
+                                let message = format!("Undefined variable '{}'.", name.get());
+                                return Err(RuntimeError::new(message));
+                            }
+                        }
+                    }
+                    OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                        let name = self.read_string(value == OpCode::DefineGlobalLong);
+                        let value = self.peek(0)?;
+                        self.globals.insert(name, value);
+                        self.pop()?;
+                    }
+                    OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                        let name = self.read_string(value == OpCode::SetGlobalLong);
+                        if !self.globals.contains_key(&name) {
+                            let message = format!("Undefined variable '{}'.", name.get());
+                            return Err(RuntimeError::new(message));
+                        }
+                        self.globals.insert(name, self.peek(0)?);
+                    }
+                    OpCode::GetUpvalue => {
+                        let slot = self.read_byte() as usize;
+                        let upvalue = self.frame().closure().upvalues[slot];
+                        let value = self.read_upvalue(upvalue);
+                        self.push(value);
+                    }
+                    OpCode::SetUpvalue => {
+                        let slot = self.read_byte() as usize;
+                        let upvalue = self.frame().closure().upvalues[slot];
+                        self.write_upvalue(upvalue, self.peek(0)?);
+                    }
+                    OpCode::GetProperty | OpCode::GetPropertyLong => {
+                        let long = value == OpCode::GetPropertyLong;
+                        let instance = match self.peek(0)? {
+                            Value::Obj(obj) if obj.as_instance().is_some() => obj,
+                            Value::Obj(obj) if obj.as_foreign().is_some() => {
+                                let name = self.read_string(long);
+                                let property = &name.as_string().unwrap().chars;
+                                let value = match obj.as_foreign().unwrap().get_property(self, property)? {
+                                    Some(value) => self.check_returned(value)?,
+                                    None => Value::Obj(self.allocate(ObjKind::ForeignMethod(ObjForeignMethod {
+                                        receiver: obj,
+                                        name,
+                                    }))),
+                                };
+                                self.pop()?; // Object.
+                                self.push(value);
+                                continue;
+                            }
+                            _ => return Err(RuntimeError::new("Only instances have properties.")),
+                        };
+
+                        let name = self.read_string(long);
+                        let instance = instance.as_instance().unwrap();
+                        let field = instance.fields.borrow().get(&name).copied();
+                        match field {
+                            Some(value) => {
+                                self.pop()?; // Instance.
+                                self.push(value);
+                            }
+                            None => self.bind_method(instance.class, name)?,
+                        }
+                    }
+                    OpCode::SetProperty | OpCode::SetPropertyLong => {
+                        let long = value == OpCode::SetPropertyLong;
+                        let instance = match self.peek(1)? {
+                            Value::Obj(obj) if obj.as_instance().is_some() => obj,
+                            Value::Obj(obj) if obj.as_foreign().is_some() => {
+                                let name = self.read_string(long);
+                                let property = &name.as_string().unwrap().chars;
+                                let value = self.peek(0)?;
+                                obj.as_foreign().unwrap().set_property(self, property, value)?;
+
+                                self.pop()?;
+                                self.pop()?; // Object.
+                                self.push(value);
+                                continue;
+                            }
+                            _ => return Err(RuntimeError::new("Only instances have fields.")),
+                        };
+
+                        let name = self.read_string(long);
+                        instance.as_instance().unwrap().fields.borrow_mut().insert(name, self.peek(0)?);
+
+                        let value = self.pop()?;
+                        self.pop()?; // Instance.
+                        self.push(value);
+                    }
+                    OpCode::GetSuper | OpCode::GetSuperLong => {
+                        let name = self.read_string(value == OpCode::GetSuperLong);
+                        let superclass = match self.pop()? {
+                            Value::Obj(superclass) => superclass,
+                            _ => unreachable!("'super' always holds a class."),
+                        };
+
+                        self.bind_method(superclass, name)?;
+                    }
+                    OpCode::Equal => {
+                        let b = self.pop()?;
+                        let a = self.pop()?;
+                        self.push(Value::Bool(a == b));
+                    }
+                    OpCode::Greater => self.binary_op(Value::Bool, |a, b| a > b)?,
+                    OpCode::Less => self.binary_op(Value::Bool, |a, b| a < b)?,
+                    OpCode::Add => {
+                        if self.peek(0)?.is_string() && self.peek(1)?.is_string() {
+                            self.concatenate()?;
+                        } else if let (Value::Number(a), Value::Number(b)) = (self.peek(1)?, self.peek(0)?) {
+                            self.pop()?;
+                            self.pop()?;
+                            self.push(Value::Number(a + b));
+                        } else {
+                            return Err(RuntimeError::new("Operands must be two numbers or two strings."));
+                        }
+                    }
+                    OpCode::Subtract => self.binary_op(Value::Number, |a, b| a - b)?,
+                    OpCode::Multiply => self.binary_op(Value::Number, |a, b| a * b)?,
+                    OpCode::Divide => self.binary_op(Value::Number, |a, b| a / b)?,
+                    OpCode::Not => {
+                        let value = self.pop()?;
+                        self.push(Value::Bool(value.is_falsey()));
+                    }
+                    OpCode::Negate => match self.peek(0)? {
+                        Value::Number(value) => {
+                            self.pop()?;
+                            self.push(Value::Number(-value));
+                        }
+                        _ => return Err(RuntimeError::new("Operand must be a number.")),
+                    },
+                    OpCode::Print => {
+                        let value = self.pop()?;
+                        writeln!(self.output, "{}", value.show()).map_err(output_error)?;
+                    }
+                    OpCode::Jump => {
+                        let offset = self.read_short();
+                        self.frame_mut().ip += offset as usize;
+                    }
+                    OpCode::JumpIfFalse => {
+                        let offset = self.read_short();
+                        if self.peek(0)?.is_falsey() {
+                            self.frame_mut().ip += offset as usize;
+                        }
+                    }
+                    OpCode::Loop => {
+                        let offset = self.read_short();
+                        self.frame_mut().ip -= offset as usize;
+                    }
+                    OpCode::Call => {
+                        let arg_count = self.read_byte() as usize;
+                        self.call_value(self.peek(arg_count)?, arg_count)?;
+                    }
+                    OpCode::Invoke | OpCode::InvokeLong => {
+                        let method = self.read_string(value == OpCode::InvokeLong);
+                        let arg_count = self.read_byte() as usize;
+                        self.invoke_method(method, arg_count)?;
+                    }
+                    OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+                        let method = self.read_string(value == OpCode::SuperInvokeLong);
+                        let arg_count = self.read_byte() as usize;
+                        let superclass = match self.pop()? {
+                            Value::Obj(superclass) => superclass,
+                            _ => unreachable!("'super' always holds a class."),
+                        };
+
+                        self.invoke_from_class(superclass, method, arg_count)?;
+                    }
+                    OpCode::Closure | OpCode::ClosureLong => {
+                        let function = match self.read_constant(value == OpCode::ClosureLong) {
+                            Value::Obj(function) => function,
+                            _ => unreachable!("Closures are always made from functions."),
+                        };
+
+                        let upvalue_count = function.as_function().unwrap().upvalue_count;
+                        let mut upvalues = Vec::with_capacity(upvalue_count);
+                        for _ in 0..upvalue_count {
+                            let is_local = self.read_byte() == 1;
+                            let index = self.read_byte() as usize;
+                            if is_local {
+                                let slot = self.frame().slot_base + index;
+                                upvalues.push(self.capture_upvalue(slot));
+                            } else {
+                                upvalues.push(self.frame().closure().upvalues[index]);
+                            }
+                        }
+
+                        let closure = self.allocate(ObjKind::Closure(ObjClosure {
+                            function,
+                            upvalues,
+                        }));
+                        self.push(Value::Obj(closure));
+                    }
+                    OpCode::CloseUpvalue => {
+                        self.close_upvalues(self.stack.len() - 1);
+                        self.pop()?;
                     }
                     OpCode::Return => {
-                        if self.stack.len() > 0 {
-                            debug::print_value(self.pop());
+                        let result = self.pop()?;
+                        let frame = self.frames.pop().unwrap();
+                        self.close_upvalues(frame.slot_base);
+
+                        self.stack.truncate(frame.slot_base);
+                        self.push(result);
+
+                        if self.frames.len() == base {
+                            return Ok(());
                         }
-                        println!();
-                        return InterpretResult::Ok;
+                    }
+                    OpCode::Class | OpCode::ClassLong => {
+                        let name = self.read_string(value == OpCode::ClassLong);
+                        let class = self.allocate(ObjKind::Class(ObjClass {
+                            name,
+                            methods: RefCell::new(HashMap::new()),
+                        }));
+                        self.push(Value::Obj(class));
+                    }
+                    OpCode::Inherit => {
+                        let superclass = match self.peek(1)? {
+                            Value::Obj(obj) if obj.as_class().is_some() => obj,
+                            _ => return Err(RuntimeError::new("Superclass must be a class.")),
+                        };
+
+                        if let Value::Obj(subclass) = self.peek(0)? {
+                            let methods = superclass.as_class().unwrap().methods.borrow().clone();
+                            subclass.as_class().unwrap().methods.borrow_mut().extend(methods);
+                        }
+                        self.pop()?; // Subclass.
+                    }
+                    OpCode::Method | OpCode::MethodLong => {
+                        let name = self.read_string(value == OpCode::MethodLong);
+                        self.define_method(name)?;
                     }
                 },
-                None => return InterpretResult::RuntimeError,
+                None => {
+                    let message = format!("Unknown opcode {}.", instruction_byte);
+                    return Err(RuntimeError::new(message));
+                }
+            }
+        }
+    }
+
+    fn trace_instruction(&mut self) -> io::Result<()> {
+        let out = &mut *self.debug_output;
+        write!(out, "          ")?;
+
+        for slot in &self.stack {
+            write!(out, "[ ")?;
+            debug::print_value(out, *slot)?;
+            write!(out, " ]")?;
+        }
+
+        writeln!(out)?;
+
+        let frame = self.frames.last().unwrap();
+        debug::disassemble_instruction(out, frame.chunk(), frame.ip)?;
+        return Ok(());
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), RuntimeError> {
+        if let Value::Obj(obj) = callee {
+            match obj.get().kind {
+                ObjKind::Closure(_) => return self.call_closure(obj, arg_count),
+                ObjKind::Native(ref native) => return self.call_native(native, arg_count),
+                ObjKind::ForeignClass(ref class) => return self.call_foreign_class(class, arg_count),
+                ObjKind::ForeignMethod(ref method) => {
+                    // The method stays in the callee slot, keeping the
+                    // receiver and name alive for the whole call.
+                    return self.invoke_foreign(method.receiver, method.name, arg_count);
+                }
+                ObjKind::BoundMethod(ref bound) => {
+                    let slot = self.stack.len() - arg_count - 1;
+                    self.stack[slot] = bound.receiver;
+                    return self.call_closure(bound.method, arg_count);
+                }
+                ObjKind::Class(ref class) => {
+                    let instance = self.allocate(ObjKind::Instance(ObjInstance {
+                        class: obj,
+                        fields: RefCell::new(HashMap::new()),
+                    }));
+                    let slot = self.stack.len() - arg_count - 1;
+                    self.stack[slot] = Value::Obj(instance);
+
+                    let initializer = class.methods.borrow().get(&self.init_string).copied();
+                    return match initializer {
+                        Some(Value::Obj(initializer)) => self.call_closure(initializer, arg_count),
+                        _ if arg_count != 0 => {
+                            let message = format!("Expected 0 arguments but got {}.", arg_count);
+                            Err(RuntimeError::new(message))
+                        }
+                        _ => Ok(()),
+                    };
+                }
+                _ => {}
+            }
+        }
+
+        return Err(RuntimeError::new("Can only call functions and classes."));
+    }
+
+    fn call_native(&mut self, native: &ObjNative, arg_count: usize) -> Result<(), RuntimeError> {
+        if arg_count != native.arity {
+            let message = format!("Expected {} arguments but got {}.", native.arity, arg_count);
+            return Err(RuntimeError::new(message));
+        }
+
+        // The arguments stay on the stack during the call so they remain
+        // rooted if the native allocates.
+        let args_start = self.stack.len() - arg_count;
+        let args = self.stack[args_start..].to_vec();
+        let result = (native.function)(self, &args)?;
+        let result = self.check_returned(result)?;
+
+        self.stack.truncate(args_start - 1);
+        self.push(result);
+        return Ok(());
+    }
+
+    fn call_foreign_class(&mut self, class: &ObjForeignClass, arg_count: usize) -> Result<(), RuntimeError> {
+        if arg_count != class.arity {
+            let message = format!("Expected {} arguments but got {}.", class.arity, arg_count);
+            return Err(RuntimeError::new(message));
+        }
+
+        // Collect while the arguments are still on the stack. Once the
+        // constructor has run, the values its object holds cannot be traced
+        // until the object is on the heap, so it is allocated without
+        // collecting.
+        if self.should_collect() {
+            self.collect_garbage();
+        }
+
+        let args_start = self.stack.len() - arg_count;
+        let args = self.stack[args_start..].to_vec();
+        let object = (class.constructor)(self, &args)?;
+        let object = self.heap.allocate(ObjKind::Foreign(object));
+
+        self.stack.truncate(args_start - 1);
+        self.push(Value::Obj(object));
+        return Ok(());
+    }
+
+    fn invoke_method(&mut self, name: ObjRef, arg_count: usize) -> Result<(), RuntimeError> {
+        let receiver = match self.peek(arg_count)? {
+            Value::Obj(obj) if obj.as_instance().is_some() => obj,
+            Value::Obj(obj) if obj.as_foreign().is_some() => return self.invoke_foreign(obj, name, arg_count),
+            _ => return Err(RuntimeError::new("Only instances have methods.")),
+        };
+
+        let instance = receiver.as_instance().unwrap();
+        let field = instance.fields.borrow().get(&name).copied();
+        if let Some(value) = field {
+            // A field holding a callable shadows any method of the same name.
+            let slot = self.stack.len() - arg_count - 1;
+            self.stack[slot] = value;
+            return self.call_value(value, arg_count);
+        }
+
+        return self.invoke_from_class(instance.class, name, arg_count);
+    }
+
+    fn invoke_foreign(&mut self, receiver: ObjRef, name: ObjRef, arg_count: usize) -> Result<(), RuntimeError> {
+        // As with natives, the arguments stay on the stack during the call,
+        // below them the receiver or the method object that holds it.
+        let args_start = self.stack.len() - arg_count;
+        let args = self.stack[args_start..].to_vec();
+        let method = &name.as_string().unwrap().chars;
+        let result = receiver.as_foreign().unwrap().invoke(self, method, &args)?;
+        let result = self.check_returned(result)?;
+
+        self.stack.truncate(args_start - 1);
+        self.push(result);
+        return Ok(());
+    }
+
+    fn invoke_from_class(&mut self, class: ObjRef, name: ObjRef, arg_count: usize) -> Result<(), RuntimeError> {
+        let method = class.as_class().unwrap().methods.borrow().get(&name).copied();
+        match method {
+            Some(Value::Obj(method)) => return self.call_closure(method, arg_count),
+            _ => {
+                let message = format!("Undefined property '{}'.", name.get());
+                return Err(RuntimeError::new(message));
+            }
+        }
+    }
+
+    /// Replaces the instance on top of the stack with its method `name`
+    /// bound to it.
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<(), RuntimeError> {
+        let method = class.as_class().unwrap().methods.borrow().get(&name).copied();
+        let method = match method {
+            Some(Value::Obj(method)) => method,
+            _ => {
+                let message = format!("Undefined property '{}'.", name.get());
+                return Err(RuntimeError::new(message));
+            }
+        };
+
+        let bound = self.allocate(ObjKind::BoundMethod(ObjBoundMethod {
+            receiver: self.peek(0)?,
+            method,
+        }));
+        self.pop()?;
+        self.push(Value::Obj(bound));
+        return Ok(());
+    }
+
+    fn define_method(&mut self, name: ObjRef) -> Result<(), RuntimeError> {
+        let method = self.peek(0)?;
+        if let Value::Obj(class) = self.peek(1)? {
+            class.as_class().unwrap().methods.borrow_mut().insert(name, method);
+        }
+        self.pop()?;
+        return Ok(());
+    }
+
+    fn call_closure(&mut self, closure: ObjRef, arg_count: usize) -> Result<(), RuntimeError> {
+        let function = closure.as_closure().unwrap().function;
+        let arity = function.as_function().unwrap().arity;
+        if arg_count != arity {
+            let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+            return Err(RuntimeError::new(message));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(RuntimeError::new("Stack overflow."));
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slot_base: self.stack.len() - arg_count - 1,
+        });
+        return Ok(());
+    }
+
+    fn capture_upvalue(&mut self, location: usize) -> ObjRef {
+        let mut insert_at = self.open_upvalues.len();
+        for (index, upvalue) in self.open_upvalues.iter().enumerate().rev() {
+            let upvalue_location = upvalue.as_upvalue().unwrap().location;
+            if upvalue_location == location {
+                return *upvalue;
+            }
+
+            if upvalue_location < location {
+                break;
+            }
+
+            insert_at = index;
+        }
+
+        let upvalue = self.allocate(ObjKind::Upvalue(ObjUpvalue {
+            location,
+            closed: Cell::new(None),
+        }));
+        self.open_upvalues.insert(insert_at, upvalue);
+        return upvalue;
+    }
+
+    /// Closes every open upvalue that refers to `last` or a slot above it.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let upvalue = upvalue.as_upvalue().unwrap();
+            if upvalue.location < last {
+                break;
             }
+
+            upvalue.closed.set(Some(self.stack[upvalue.location]));
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn read_upvalue(&self, upvalue: ObjRef) -> Value {
+        let upvalue = upvalue.as_upvalue().unwrap();
+        return match upvalue.closed.get() {
+            Some(value) => value,
+            None => self.stack[upvalue.location],
+        };
+    }
+
+    fn write_upvalue(&mut self, upvalue: ObjRef, value: Value) {
+        let upvalue = upvalue.as_upvalue().unwrap();
+        match upvalue.closed.get() {
+            Some(_) => upvalue.closed.set(Some(value)),
+            None => self.stack[upvalue.location] = value,
+        }
+    }
+
+    fn should_collect(&self) -> bool {
+        return self.options.stress_gc || self.heap.should_collect();
+    }
+
+    pub(crate) fn allocate(&mut self, kind: ObjKind) -> ObjRef {
+        if self.should_collect() {
+            self.collect_garbage();
+        }
+
+        return self.heap.allocate(kind);
+    }
+
+    pub(crate) fn take_string(&mut self, chars: String) -> ObjRef {
+        if self.should_collect() {
+            self.collect_garbage();
+        }
+
+        return self.heap.take_string(chars);
+    }
+
+    fn collect_garbage(&mut self) {
+        for value in &self.stack {
+            self.heap.mark_value(*value);
+        }
+
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
         }
+
+        for upvalue in &self.open_upvalues {
+            self.heap.mark_object(*upvalue);
+        }
+
+        for (name, value) in &self.globals {
+            self.heap.mark_object(*name);
+            self.heap.mark_value(*value);
+        }
+
+        self.heap.mark_object(self.init_string);
+        self.roots.mark(&mut self.heap);
+
+        self.heap.collect();
+        self.roots.sweep();
     }
 
-    fn binary_op<F>(&mut self, op: F)
+    fn binary_op<T, F>(&mut self, value_type: fn(T) -> Value, op: F) -> Result<(), RuntimeError>
     where
-        F: Fn(Value, Value) -> Value,
+        F: Fn(f64, f64) -> T,
     {
-        let b = self.pop();
-        let a = self.pop();
-        self.push(op(a, b));
+        match (self.peek(1)?, self.peek(0)?) {
+            (Value::Number(a), Value::Number(b)) => {
+                self.pop()?;
+                self.pop()?;
+                self.push(value_type(op(a, b)));
+                return Ok(());
+            }
+            _ => return Err(RuntimeError::new("Operands must be numbers.")),
+        }
+    }
+
+    fn concatenate(&mut self) -> Result<(), RuntimeError> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+
+        let mut chars = a.as_string().unwrap().chars.clone();
+        chars.push_str(&b.as_string().unwrap().chars);
+
+        let result = self.take_string(chars);
+        self.push(Value::Obj(result));
+        return Ok(());
+    }
+
+    /// Describes the frames above `base`, innermost first.
+    fn stack_trace(&self, base: usize) -> Vec<TraceFrame> {
+        return self.frames[base..]
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = frame.closure().function.as_function().unwrap();
+
+                // The ip has already moved past the failing instruction.
+                let instruction = frame.ip.saturating_sub(1);
+
+                TraceFrame {
+                    function: function.name.map(|name| name.get().to_string()),
+                    line: frame.chunk().get_line(instruction),
+                    column: frame.chunk().get_column(instruction),
+                    in_source: false,
+                    script: function.script,
+                }
+            })
+            .collect();
     }
 
-    pub fn push(&mut self, value: Value) {
+    pub(crate) fn push(&mut self, value: Value) {
         self.stack.push(value);
     }
 
-    pub fn pop(&mut self) -> Value {
-        self.stack.pop().unwrap()
+    pub(crate) fn pop(&mut self) -> Result<Value, RuntimeError> {
+        return self.stack.pop().ok_or_else(|| RuntimeError::new("Stack underflow."));
+    }
+
+    fn peek(&self, distance: usize) -> Result<Value, RuntimeError> {
+        if distance >= self.stack.len() {
+            return Err(RuntimeError::new("Stack underflow."));
+        }
+
+        return Ok(self.stack[self.stack.len() - 1 - distance]);
     }
 
-    fn read_byte(&mut self, chunk: &Chunk) -> u8 {
-        let byte: u8 = chunk.code[self.ip];
-        self.ip += 1;
+    fn frame(&self) -> &CallFrame {
+        return self.frames.last().unwrap();
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        return self.frames.last_mut().unwrap();
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let byte: u8 = frame.chunk().code[frame.ip];
+        frame.ip += 1;
         byte
     }
+
+    fn read_short(&mut self) -> u16 {
+        let high = self.read_byte() as u16;
+        let low = self.read_byte() as u16;
+        (high << 8) | low
+    }
+
+    /// Reads a constant index operand, which is three bytes wide for the long
+    /// form of an instruction.
+    fn read_index(&mut self, long: bool) -> usize {
+        if !long {
+            return self.read_byte() as usize;
+        }
+
+        let high = self.read_byte() as usize;
+        return (high << 16) | self.read_short() as usize;
+    }
+
+    fn read_constant(&mut self, long: bool) -> Value {
+        let index = self.read_index(long);
+        return self.frame().chunk().read_constant(index);
+    }
+
+    fn read_string(&mut self, long: bool) -> ObjRef {
+        match self.read_constant(long) {
+            Value::Obj(name) => name,
+            _ => unreachable!("Variable names are always string constants."),
+        }
+    }
+}
+
+impl Drop for VM {
+    fn drop(&mut self) {
+        // Handles outliving the VM may still read their objects.
+        if !self.roots.is_empty() {
+            mem::forget(mem::take(&mut self.heap));
+        }
+    }
+}
+
+fn output_error(err: io::Error) -> RuntimeError {
+    return RuntimeError::new(format!("Could not write output: {}.", err));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io::Cursor};
+
+    use super::*;
+    use crate::foreign::Tracer;
+
+    /// Collects what the VM writes so tests can inspect it.
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Output {
+        fn take(&self) -> String {
+            return String::from_utf8(mem::take(&mut *self.0.borrow_mut())).unwrap();
+        }
+    }
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            return Ok(buf.len());
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    fn vm_with_output(options: VMOptions) -> (VM, Output) {
+        let mut vm = VM::with_options(options);
+        let output = Output::default();
+        vm.set_output(output.clone());
+        return (vm, output);
+    }
+
+    /// Runs `source` and returns what it printed, failing the test on any
+    /// error.
+    fn run_with(options: VMOptions, source: &str) -> String {
+        let (mut vm, output) = vm_with_output(options);
+        match vm.interpret(source) {
+            InterpretResult::Ok => {}
+            result => panic!("{:?}", result),
+        }
+
+        return output.take();
+    }
+
+    fn run(source: &str) -> String {
+        return run_with(VMOptions::default(), source);
+    }
+
+    fn runtime_error(vm: &mut VM, source: &str) -> RuntimeError {
+        match vm.interpret(source) {
+            InterpretResult::RuntimeError(error) => return error,
+            result => panic!("Expected a runtime error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn reports_the_call_stack_of_runtime_errors() {
+        let mut vm = VM::new();
+        let error = runtime_error(
+            &mut vm,
+            "fun a() {\n  b();\n}\nfun b() {\n  c();\n}\nfun c() {\n  return nil + 1;\n}\na();\n",
+        );
+
+        assert_eq!(
+            error.to_string(),
+            "Operands must be two numbers or two strings.\n\
+             [line 8:14] in c()\n\
+             [line 5:5] in b()\n\
+             [line 2:5] in a()\n\
+             [line 10:3] in script\n"
+        );
+        assert!(error.trace.iter().all(|frame| frame.in_source));
+
+        let diagnostic = error.to_diagnostic();
+        assert_eq!((diagnostic.line, diagnostic.column), (8, 14));
+        assert_eq!(diagnostic.notes.len(), 4);
+    }
+
+    #[test]
+    fn tells_apart_frames_from_earlier_scripts() {
+        let mut vm = VM::new();
+        vm.interpret("fun fail() {\n  return -\"a\";\n}");
+        let error = runtime_error(&mut vm, "\nfail();");
+
+        assert_eq!(error.message, "Operand must be a number.");
+        assert_eq!(error.trace.len(), 2);
+        assert_eq!((error.trace[0].line, error.trace[0].in_source), (2, false));
+        assert_eq!((error.trace[1].line, error.trace[1].in_source), (2, true));
+
+        // The position would point into the other script.
+        let diagnostic = error.to_diagnostic();
+        assert_eq!((diagnostic.line, diagnostic.column), (0, 0));
+    }
+
+    #[test]
+    fn carries_on_after_a_runtime_error() {
+        let (mut vm, output) = vm_with_output(VMOptions::default());
+        vm.interpret("var kept = \"yes\";");
+        runtime_error(&mut vm, "fun f(a) { return a(); } f(1);");
+
+        vm.interpret("print kept; print 1 + 2;");
+        assert_eq!(output.take(), "yes\n3\n");
+    }
+
+    /// A script whose chunks have more than 256 constants, so that every
+    /// instruction taking a constant needs its long form.
+    fn long_operands_script() -> String {
+        // Fills a function's constant table with distinct numbers.
+        let filler: String = (0..300).map(|i| format!("t = t + {}.5; ", i)).collect();
+        let filler = format!("var t = 0; {}", filler);
+
+        let mut source: String = (0..300).map(|i| format!("var g{} = {};\n", i, i)).collect();
+        source.push_str(&format!(
+            r#"
+            g299 = g299 + g0 + 1;
+            print g299;
+
+            class A {{
+                name() {{ return "A"; }}
+            }}
+
+            class B < A {{
+                init() {{ this.field = 1; }}
+                name() {{ {filler} return super.name() + "B"; }}
+                bound() {{ {filler} var method = super.name; return method(); }}
+            }}
+
+            var b = B();
+            b.field = b.field + 1;
+            print b.field;
+            print b.name();
+            print b.bound();
+
+            fun outer() {{
+                var x = "closed";
+                fun inner() {{ return x; }}
+                return inner;
+            }}
+            print outer()();
+            "#
+        ));
+        return source;
+    }
+
+    #[test]
+    fn runs_chunks_with_more_than_256_constants() {
+        assert_eq!(run(&long_operands_script()), "300\n2\nAB\nA\nclosed\n");
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn uses_every_long_instruction() {
+        let mut options = VMOptions::default();
+        options.compile.dump_bytecode = true;
+        let (mut vm, _) = vm_with_output(options);
+        let dump = Output::default();
+        vm.set_debug_output(dump.clone());
+
+        vm.interpret(&long_operands_script());
+        let dump = dump.take();
+        for op in [
+            "OP_CONSTANT_LONG",
+            "OP_GET_GLOBAL_LONG",
+            "OP_DEFINE_GLOBAL_LONG",
+            "OP_SET_GLOBAL_LONG",
+            "OP_GET_PROPERTY_LONG",
+            "OP_SET_PROPERTY_LONG",
+            "OP_GET_SUPER_LONG",
+            "OP_INVOKE_LONG",
+            "OP_SUPER_INVOKE_LONG",
+            "OP_CLOSURE_LONG",
+            "OP_CLASS_LONG",
+            "OP_METHOD_LONG",
+        ] {
+            assert!(dump.contains(op), "{} not used", op);
+        }
+    }
+
+    #[test]
+    fn keeps_live_objects_when_collecting_at_every_allocation() {
+        let source = r#"
+            class Node {
+                init(value, next) {
+                    this.value = value;
+                    this.next = next;
+                }
+            }
+
+            class Greeter {
+                init(name) { this.name = name; }
+                greet() { return "hi " + this.name; }
+            }
+
+            class Loud < Greeter {
+                greet() { return super.greet() + "!"; }
+            }
+
+            fun counter() {
+                var count = 0;
+                fun increment() {
+                    count = count + 1;
+                    return count;
+                }
+                return increment;
+            }
+
+            var next = counter();
+            var list = nil;
+            for (var i = 0; i < 100; i = i + 1) {
+                list = Node("n" + str(next()), list);
+            }
+
+            var ends = "";
+            while (list != nil) {
+                if (list.value == "n1" or list.value == "n100") ends = ends + list.value + " ";
+                list = list.next;
+            }
+            print ends;
+
+            var greet = Loud("lox").greet;
+            // Reuse the memory of anything freed too early.
+            for (var i = 0; i < 100; i = i + 1) Greeter("x" + str(i));
+            print greet();
+            print next();
+        "#;
+        let expected = "n100 n1 \nhi lox!\n101\n";
+
+        let stress = VMOptions {
+            stress_gc: true,
+            ..VMOptions::default()
+        };
+        assert_eq!(run_with(stress, source), expected);
+        assert_eq!(run(source), expected);
+    }
+
+    #[test]
+    fn keeps_globals_across_scripts_while_collecting() {
+        let stress = VMOptions {
+            stress_gc: true,
+            ..VMOptions::default()
+        };
+        let (mut vm, output) = vm_with_output(stress);
+
+        vm.interpret("var names = \"\"; fun add(name) { names = names + name + \",\"; }");
+        for i in 0..20 {
+            vm.interpret(&format!("add(\"n{}\");", i));
+        }
+        vm.interpret("print names;");
+
+        let expected: String = (0..20).map(|i| format!("n{},", i)).collect();
+        assert_eq!(output.take(), expected + "\n");
+    }
+
+    thread_local! {
+        // A string the VMs under test have no way to tell is alive.
+        static ELSEWHERE: Handle = VM::new().new_string("elsewhere");
+    }
+
+    fn string_from_elsewhere(_vm: &mut VM, _args: &[Value]) -> Result<Value, RuntimeError> {
+        return Ok(ELSEWHERE.with(|string| string.value()));
+    }
+
+    #[test]
+    fn compiles_scripts_to_run_later() {
+        let (mut vm, output) = vm_with_output(VMOptions::default());
+        let script = vm.compile("var n = 0; n = n + 1; print n;").unwrap();
+
+        vm.call(&script, &[]).unwrap();
+        vm.call(&script, &[]).unwrap();
+        assert_eq!(output.take(), "1\n1\n");
+
+        let diagnostics = vm.compile("print ;").unwrap_err();
+        assert_eq!(diagnostics[0].message, "Expect expression.");
+    }
+
+    #[test]
+    fn keeps_handled_values_alive_through_collections() {
+        let (mut vm, output) = vm_with_output(VMOptions::default());
+        let string = vm.new_string("kept");
+        vm.interpret("for (var i = 0; i < 30000; i = i + 1) str(i);");
+        assert_eq!(string.as_str(), Some("kept"));
+
+        vm.interpret("print str(12) + \"!\";");
+        assert_eq!(output.take(), "12!\n");
+    }
+
+    #[test]
+    fn rejects_objects_natives_return_from_elsewhere() {
+        let mut vm = VM::new();
+        vm.define_native("elsewhere", 0, string_from_elsewhere);
+
+        let error = runtime_error(&mut vm, "print elsewhere();");
+        assert_eq!(error.message, "Native code returned an object that is not alive in this VM.");
+    }
+
+    #[test]
+    #[should_panic(expected = "Value is not alive in this VM.")]
+    fn refuses_handles_to_objects_from_elsewhere() {
+        let vm = VM::new();
+        vm.handle(ELSEWHERE.with(|string| string.value()));
+    }
+
+    #[test]
+    fn reads_and_writes_globals() {
+        let (mut vm, output) = vm_with_output(VMOptions::default());
+        vm.interpret("var greeting = \"hello\";");
+
+        let greeting = vm.get_global("greeting").unwrap();
+        assert_eq!(greeting.as_str(), Some("hello"));
+        assert!(vm.get_global("missing").is_none());
+
+        vm.set_global("copy", &greeting);
+        let number = vm.handle(Value::Number(2.0));
+        vm.set_global("number", &number);
+        vm.interpret("print copy; print number;");
+        assert_eq!(output.take(), "hello\n2\n");
+    }
+
+    #[test]
+    fn calls_functions_and_methods() {
+        let mut vm = VM::new();
+        vm.interpret(
+            "fun add(a, b) { return a + b; }\n\
+             class Counter { init() { this.count = 0; } bump(by) { this.count = this.count + by; return this.count; } }\n\
+             var counter = Counter();",
+        );
+
+        let add = vm.get_global("add").unwrap();
+        let sum = vm.call(&add, &[Value::Number(1.0), Value::Number(2.0)]).unwrap();
+        assert_eq!(sum.value(), Value::Number(3.0));
+
+        let counter = vm.get_global("counter").unwrap();
+        vm.invoke(&counter, "bump", &[Value::Number(2.0)]).unwrap();
+        let count = vm.invoke(&counter, "bump", &[Value::Number(3.0)]).unwrap();
+        assert_eq!(count.value(), Value::Number(5.0));
+    }
+
+    #[test]
+    fn unwinds_calls_that_fail() {
+        let (mut vm, output) = vm_with_output(VMOptions::default());
+        vm.interpret("fun fail(x) {\n  return x + 1;\n}\nclass A {}\nvar a = A();");
+
+        let fail = vm.get_global("fail").unwrap();
+        let error = vm.call(&fail, &[Value::Nil]).unwrap_err();
+        assert_eq!(error.message, "Operands must be two numbers or two strings.");
+        assert_eq!(error.trace[0].line, 2);
+
+        let a = vm.get_global("a").unwrap();
+        let error = vm.invoke(&a, "missing", &[]).unwrap_err();
+        assert_eq!(error.message, "Undefined property 'missing'.");
+
+        vm.interpret("print fail(1);");
+        assert_eq!(output.take(), "2\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "Handle belongs to another VM.")]
+    fn refuses_handles_from_another_vm() {
+        let mut other = VM::new();
+        other.interpret("fun f() {}");
+        let f = other.get_global("f").unwrap();
+
+        let mut vm = VM::new();
+        vm.call(&f, &[]).ok();
+    }
+
+    /// Holds a value for scripts, and answers any other method with its
+    /// name.
+    struct Slot {
+        value: RefCell<Value>,
+    }
+
+    impl ForeignObject for Slot {
+        fn to_string(&self) -> String {
+            return "<slot>".to_owned();
+        }
+
+        fn get_property(&self, _vm: &mut VM, name: &str) -> Result<Option<Value>, RuntimeError> {
+            return match name {
+                "empty" => Ok(Some(Value::Bool(self.value.borrow().is_falsey()))),
+                _ => Ok(None),
+            };
+        }
+
+        fn invoke(&self, vm: &mut VM, name: &str, args: &[Value]) -> Result<Value, RuntimeError> {
+            match name {
+                "get" => {
+                    let value = *self.value.borrow();
+                    // SAFETY: the slot traces its value.
+                    return Ok(unsafe { vm.handle_unchecked(value) }.value());
+                }
+                "set" => {
+                    *self.value.borrow_mut() = args[0];
+                    return Ok(Value::Nil);
+                }
+                _ => {
+                    // Allocate first, so that a name freed too early would
+                    // be overwritten.
+                    for i in 0..10 {
+                        vm.new_string(&format!("filler {}", i));
+                    }
+                    return Ok(vm.new_string(name).value());
+                }
+            }
+        }
+
+        fn trace(&self, tracer: &mut Tracer) {
+            // SAFETY: the value came from a script and is traced from then on.
+            unsafe { tracer.mark(*self.value.borrow()) };
+        }
+    }
+
+    fn stressed_vm_with_slots() -> (VM, Output) {
+        let stress = VMOptions {
+            stress_gc: true,
+            ..VMOptions::default()
+        };
+        let (mut vm, output) = vm_with_output(stress);
+        vm.define_foreign_class("Slot", 0, |_, _| {
+            return Ok(Box::new(Slot {
+                value: RefCell::new(Value::Nil),
+            }));
+        });
+        return (vm, output);
+    }
+
+    #[test]
+    fn runs_foreign_objects() {
+        let (mut vm, output) = stressed_vm_with_slots();
+        vm.interpret(
+            "var slot = Slot();\n\
+             print slot.empty;\n\
+             slot.set(\"a\" + \"b\");\n\
+             for (var i = 0; i < 10; i = i + 1) str(i);\n\
+             print slot.get();\n\
+             print slot.empty;\n\
+             print slot;",
+        );
+        assert_eq!(output.take(), "true\nab\nfalse\n<slot>\n");
+
+        let error = runtime_error(&mut vm, "slot.value = 1;");
+        assert_eq!(error.message, "Can't set property 'value' on <slot>.");
+    }
+
+    #[test]
+    fn keeps_foreign_methods_alive_while_they_run() {
+        let (mut vm, output) = stressed_vm_with_slots();
+        // Only the method object refers to its name once `get` returns.
+        vm.interpret("var method; { fun get() { return Slot().some_method_name; } method = get(); }");
+        vm.interpret("fun take() { var taken = method; method = nil; return taken; } print take()();");
+        assert_eq!(output.take(), "some_method_name\n");
+    }
+
+    #[test]
+    fn prints_to_the_output() {
+        assert_eq!(run("print 1 + 2; print \"a\" + \"b\"; print nil;"), "3\nab\nnil\n");
+    }
+
+    #[test]
+    fn reads_lines_from_the_input() {
+        let (mut vm, output) = vm_with_output(VMOptions::default());
+        vm.set_input(Cursor::new("first\r\nsecond"));
+
+        vm.interpret("print input(); print input(); print input();");
+        assert_eq!(output.take(), "first\nsecond\nnil\n");
+    }
 }